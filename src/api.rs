@@ -0,0 +1,420 @@
+//! A Twitter API client that shares rate limits and retries between all requests.
+//!
+//! Rate limits are handled reactively: nothing is paused until a request is rate limited.
+//! Twitter reports the remaining requests and reset time in `x-rate-limit-remaining` and
+//! `x-rate-limit-reset` headers, but `twitter-v2` drops response headers, so they can't be
+//! tracked ahead of time.
+
+use crate::bot::{Error, Result};
+use crate::clock::Clock;
+use serde::de::DeserializeOwned;
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use twitter_v2::authorization::{Oauth2Client, Oauth2Token};
+use twitter_v2::TwitterApi;
+
+/// How long API requests are paused for after hitting the rate limit, by default. Twitter's
+/// limits reset in 15 minute windows. Responses carry the exact reset time in headers, but
+/// the client library doesn't expose them.
+const RATE_LIMIT_WINDOW: time::Duration = time::Duration::minutes(15);
+
+/// Retries of each request starting a run before giving up, as a failure there aborts
+/// the whole run.
+const MAX_RETRIES: u32 = 2;
+
+/// Delay before retrying a request, doubled after each attempt.
+const RETRY_DELAY: std::time::Duration = std::time::Duration::from_secs(2);
+
+/// How requests react to rate limits and transient failures.
+///
+/// Rate limits are only noticed once a request fails with `429 Too Many Requests`, as the
+/// headers reporting remaining requests aren't available. All requests then pause for a
+/// fixed `window`, rather than until the reported reset.
+#[derive(Debug, Clone)]
+pub struct RateLimitConfig {
+    /// How long to pause all requests for after one is rate limited.
+    pub window: time::Duration,
+    /// Whether a rate limited request waits for the limit to reset and is retried once.
+    /// Otherwise it fails straight away with [`Error::RateLimited`].
+    pub respect_reset: bool,
+    /// Retries of transient failures, for requests made with
+    /// [`RateLimitedApi::request_with_retries`].
+    pub max_retries: u32,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            window: RATE_LIMIT_WINDOW,
+            respect_reset: true,
+            max_retries: MAX_RETRIES,
+        }
+    }
+}
+
+/// How to refresh the login when it expires mid-run. The token must have been granted
+/// the `offline.access` scope to include a refresh token.
+#[derive(Debug, Clone)]
+pub struct TokenRefresh {
+    pub client: Oauth2Client,
+    /// Where the refreshed token is saved, so later runs can use it.
+    pub token_file: Option<PathBuf>,
+}
+
+/// Rate limit status shared by all API requests made through a [`RateLimitedApi`].
+#[derive(Debug, Default)]
+struct RateLimitState {
+    /// When requests may resume, after one was rate limited.
+    blocked_until: Option<time::OffsetDateTime>,
+    /// When each pause for the rate limit ended.
+    pauses: Vec<time::OffsetDateTime>,
+}
+
+/// Wraps the Twitter client, so every request waits out rate limits hit by any other.
+pub struct RateLimitedApi {
+    /// Replaced when the login is refreshed.
+    api: RwLock<TwitterApi<Oauth2Token>>,
+    config: RateLimitConfig,
+    refresh: Option<TokenRefresh>,
+    state: RwLock<RateLimitState>,
+    clock: Arc<dyn Clock>,
+    /// Responses returned in order instead of calling Twitter, for tests.
+    #[cfg(test)]
+    script: Option<std::sync::Mutex<std::collections::VecDeque<Scripted>>>,
+}
+
+/// A response returned instead of calling Twitter, as JSON of the expected payload.
+#[cfg(test)]
+pub(crate) type Scripted = twitter_v2::Result<serde_json::Value>;
+
+impl RateLimitedApi {
+    pub fn new(
+        access_token: Oauth2Token,
+        config: RateLimitConfig,
+        refresh: Option<TokenRefresh>,
+        clock: Arc<dyn Clock>,
+    ) -> Result<Self> {
+        if !config.window.is_positive() {
+            return Err(Error::Config("rate limit window must be positive"));
+        }
+        Ok(Self {
+            api: RwLock::new(TwitterApi::new(access_token)),
+            config,
+            refresh,
+            state: Default::default(),
+            clock,
+            #[cfg(test)]
+            script: None,
+        })
+    }
+
+    /// Return these responses in order instead of calling Twitter, ignoring the requests
+    /// made. Panics if a request is made once they run out.
+    #[cfg(test)]
+    pub(crate) fn scripted(mut self, responses: Vec<Scripted>) -> Self {
+        self.script = Some(std::sync::Mutex::new(responses.into()));
+        self
+    }
+
+    /// Send a request built from the client, or return the next scripted response.
+    async fn send<T, F, Fut>(
+        &self,
+        request: &F,
+        api: &TwitterApi<Oauth2Token>,
+    ) -> twitter_v2::Result<T>
+    where
+        T: DeserializeOwned,
+        F: Fn(&TwitterApi<Oauth2Token>) -> Fut,
+        Fut: Future<Output = twitter_v2::Result<T>>,
+    {
+        #[cfg(test)]
+        if let Some(script) = &self.script {
+            let response = script
+                .lock()
+                .expect("script lock poisoned")
+                .pop_front()
+                .expect("a scripted response for each request");
+            // Yield as a real request would, so concurrent requests interleave.
+            tokio::task::yield_now().await;
+            return response.map(|value| serde_json::from_value(value).expect("scripted payload"));
+        }
+        request(api).await
+    }
+
+    pub fn config(&self) -> &RateLimitConfig {
+        &self.config
+    }
+
+    /// When each pause for the rate limit ended, or will end.
+    pub async fn pauses(&self) -> Vec<time::OffsetDateTime> {
+        self.state.read().await.pauses.clone()
+    }
+
+    /// Wait until requests may resume, if an earlier request was rate limited.
+    async fn wait_for_rate_limit(&self) {
+        let blocked_until = self.state.read().await.blocked_until;
+        if let Some(blocked_until) = blocked_until {
+            let wait = blocked_until - self.clock.now();
+            if wait.is_positive() {
+                tokio::time::sleep(wait.unsigned_abs()).await;
+            }
+        }
+    }
+
+    /// Refresh the login after a request made with `expired` failed as unauthorized.
+    /// If another request has already refreshed it, the newer login is kept.
+    async fn refresh_login(&self, expired: &TwitterApi<Oauth2Token>) -> Result<()> {
+        let refresh = self.refresh.as_ref().ok_or(Error::NoRefreshToken)?;
+        let mut api = self.api.write().await;
+        if api.auth().access_token().secret() != expired.auth().access_token().secret() {
+            return Ok(());
+        }
+        log::info!("Twitter login has expired, refreshing it");
+        let token = crate::auth::refresh(&refresh.client, api.auth())
+            .await
+            .map_err(|error| match error {
+                twitter_v2::Error::NoRefreshToken => Error::NoRefreshToken,
+                error => Error::RefreshFailed(error),
+            })?;
+        if let Some(token_file) = &refresh.token_file {
+            if let Err(error) = crate::auth::save_token(token_file, &token) {
+                log::warn!("Failed to save refreshed token: {error:#}");
+            }
+        }
+        *api = TwitterApi::new(token);
+        Ok(())
+    }
+
+    /// Make an API request built from the client, sharing rate limits with all other
+    /// requests.
+    ///
+    /// Requests wait while the rate limit is exceeded. The first request to be rate limited
+    /// pauses all requests for a limit window, and is retried once afterwards, unless
+    /// `respect_reset` is turned off. If the login has expired and can be refreshed, the
+    /// request is retried once with the refreshed login.
+    pub async fn request<T, F, Fut>(&self, request: F) -> Result<T>
+    where
+        T: DeserializeOwned,
+        F: Fn(&TwitterApi<Oauth2Token>) -> Fut,
+        Fut: Future<Output = twitter_v2::Result<T>>,
+    {
+        let mut retried = !self.config.respect_reset;
+        let mut refreshed = false;
+        loop {
+            self.wait_for_rate_limit().await;
+            let api = self.api.read().await.clone();
+            match self.send(&request, &api).await.map_err(Error::from_client) {
+                Err(Error::AuthExpired) if !refreshed => {
+                    self.refresh_login(&api).await?;
+                    refreshed = true;
+                }
+                Err(Error::RateLimited { .. }) if !retried => {
+                    let now = self.clock.now();
+                    let mut state = self.state.write().await;
+                    match state.blocked_until {
+                        // Another request already paused for this window.
+                        Some(blocked_until) if blocked_until > now => {}
+                        _ => {
+                            let blocked_until = now + self.config.window;
+                            log::warn!(
+                                "Rate limited by Twitter, pausing requests until {blocked_until}"
+                            );
+                            state.blocked_until = Some(blocked_until);
+                            state.pauses.push(blocked_until);
+                        }
+                    }
+                    retried = true;
+                }
+                Err(Error::RateLimited { .. }) => {
+                    let reset = self.state.read().await.blocked_until;
+                    return Err(Error::RateLimited { reset });
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Make an API request as with `request`, also retrying transient failures up to
+    /// `max_retries` times with backoff.
+    pub async fn request_with_retries<T, F, Fut>(&self, description: &str, request: F) -> Result<T>
+    where
+        T: DeserializeOwned,
+        F: Fn(&TwitterApi<Oauth2Token>) -> Fut,
+        Fut: Future<Output = twitter_v2::Result<T>>,
+    {
+        let mut delay = RETRY_DELAY;
+        let mut retries = 0;
+        loop {
+            match self.request(&request).await {
+                Err(error) if error.is_transient() && retries < self.config.max_retries => {
+                    log::warn!(
+                        "Failed to {description}, retrying in {}: {error:#}",
+                        indicatif::HumanDuration(delay)
+                    );
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                    retries += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::FixedClock;
+    use crate::fixtures;
+    use pretty_assertions::assert_eq;
+    use reqwest::StatusCode;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// An API whose clock is stopped at [`fixtures::created_at`].
+    fn api(config: RateLimitConfig) -> RateLimitedApi {
+        let clock = Arc::new(FixedClock(fixtures::created_at()));
+        RateLimitedApi::new(fixtures::token(), config, None, clock).unwrap()
+    }
+
+    /// Make a request with retries that fails with each status in turn, then succeeds.
+    /// Returns the result and the number of attempts made.
+    async fn request_failing_with(
+        api: &RateLimitedApi,
+        statuses: &[StatusCode],
+    ) -> (Result<String>, usize) {
+        let attempts = AtomicUsize::new(0);
+        let result = api
+            .request_with_retries("look up the logged in user", |_| {
+                let attempt = attempts.fetch_add(1, Ordering::SeqCst);
+                let status = statuses.get(attempt).copied();
+                async move {
+                    match status {
+                        Some(status) => Err(fixtures::api_error(status)),
+                        None => Ok("magpie".to_owned()),
+                    }
+                }
+            })
+            .await;
+        (result, attempts.load(Ordering::SeqCst))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn transient_failure_is_retried() {
+        let api = api(RateLimitConfig::default());
+        let (result, attempts) =
+            request_failing_with(&api, &[StatusCode::SERVICE_UNAVAILABLE]).await;
+        assert_eq!(result.unwrap(), "magpie");
+        assert_eq!(attempts, 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn transient_failures_give_up_after_max_retries() {
+        let api = api(RateLimitConfig {
+            max_retries: 1,
+            ..Default::default()
+        });
+        let (result, attempts) = request_failing_with(
+            &api,
+            &[StatusCode::BAD_GATEWAY, StatusCode::SERVICE_UNAVAILABLE],
+        )
+        .await;
+        assert!(result.unwrap_err().is_transient());
+        assert_eq!(attempts, 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn client_errors_are_not_retried() {
+        let api = api(RateLimitConfig::default());
+        let (result, attempts) = request_failing_with(&api, &[StatusCode::FORBIDDEN]).await;
+        assert!(matches!(result, Err(Error::TwitterClient(_))));
+        assert_eq!(attempts, 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn expired_login_without_refresh_is_not_retried() {
+        let api = api(RateLimitConfig::default());
+        let (result, attempts) = request_failing_with(&api, &[StatusCode::UNAUTHORIZED]).await;
+        assert!(matches!(result, Err(Error::NoRefreshToken)));
+        assert!(result.unwrap_err().to_string().contains("--offline-access"));
+        assert_eq!(attempts, 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rate_limit_pauses_for_window_from_clock() {
+        let api = api(RateLimitConfig::default());
+        let (result, attempts) = request_failing_with(&api, &[StatusCode::TOO_MANY_REQUESTS]).await;
+        assert_eq!(result.unwrap(), "magpie");
+        assert_eq!(attempts, 2);
+        assert_eq!(
+            api.pauses().await,
+            vec![fixtures::created_at() + RATE_LIMIT_WINDOW]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rate_limit_fails_after_one_pause() {
+        let api = api(RateLimitConfig {
+            window: time::Duration::minutes(1),
+            ..Default::default()
+        });
+        let (result, attempts) = request_failing_with(
+            &api,
+            &[StatusCode::TOO_MANY_REQUESTS, StatusCode::TOO_MANY_REQUESTS],
+        )
+        .await;
+        let reset = fixtures::created_at() + time::Duration::minutes(1);
+        assert!(matches!(result, Err(Error::RateLimited { reset: Some(r) }) if r == reset));
+        assert_eq!(attempts, 2);
+        assert_eq!(api.pauses().await, vec![reset]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rate_limit_fails_straight_away_without_respect_reset() {
+        let api = api(RateLimitConfig {
+            respect_reset: false,
+            ..Default::default()
+        });
+        let (result, attempts) = request_failing_with(&api, &[StatusCode::TOO_MANY_REQUESTS]).await;
+        assert!(matches!(result, Err(Error::RateLimited { reset: None })));
+        assert_eq!(attempts, 1);
+        assert!(api.pauses().await.is_empty());
+    }
+
+    #[test]
+    fn window_must_be_positive() {
+        let config = RateLimitConfig {
+            window: time::Duration::ZERO,
+            ..Default::default()
+        };
+        let clock = Arc::new(FixedClock(fixtures::created_at()));
+        assert!(matches!(
+            RateLimitedApi::new(fixtures::token(), config, None, clock),
+            Err(Error::Config(_))
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn expired_login_without_refresh_token_is_not_refreshed() {
+        let refresh = TokenRefresh {
+            client: Oauth2Client::new(
+                "client",
+                "secret",
+                "http://127.0.0.1/callback".parse().unwrap(),
+            ),
+            token_file: None,
+        };
+        let clock = Arc::new(FixedClock(fixtures::created_at()));
+        let api = RateLimitedApi::new(
+            fixtures::token(),
+            RateLimitConfig::default(),
+            Some(refresh),
+            clock,
+        )
+        .unwrap();
+        let (result, attempts) = request_failing_with(&api, &[StatusCode::UNAUTHORIZED]).await;
+        assert!(matches!(result, Err(Error::NoRefreshToken)));
+        assert_eq!(attempts, 1);
+    }
+}
@@ -1,3 +1,8 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::net::{IpAddr, SocketAddr, TcpListener};
+use std::path::{Path, PathBuf};
 use thiserror::Error;
 use twitter_v2::authorization::{Oauth2Client, Oauth2Token, Scope};
 use twitter_v2::oauth2::{AuthorizationCode, CsrfToken, PkceCodeChallenge, PkceCodeVerifier};
@@ -6,6 +11,18 @@ use twitter_v2::oauth2::{AuthorizationCode, CsrfToken, PkceCodeChallenge, PkceCo
 pub enum Error {
     #[error("Missing required environment variable '{}'", key)]
     MissingEnvironment { key: &'static str },
+    #[error("Failed to access token file '{}'", path.display())]
+    TokenFile {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("No login was started for '{}'", path.display())]
+    LoginNotStarted { path: PathBuf },
+    #[error("Token file '{}' is malformed", path.display())]
+    TokenMalformed {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -14,6 +31,28 @@ fn require_environment(key: &'static str) -> Result<String> {
     std::env::var(key).map_err(|_| Error::MissingEnvironment { key })
 }
 
+/// Find a port to receive the OAuth callback on, starting at `port` and trying up to
+/// `fallbacks` following ports if it is already in use.
+///
+/// Each port tried must be registered as a callback URI with Twitter for login to succeed.
+pub fn find_callback_port(host: IpAddr, port: u16, fallbacks: u16) -> std::io::Result<u16> {
+    let mut candidate = port;
+    loop {
+        match TcpListener::bind(SocketAddr::from((host, candidate))) {
+            Ok(_) => return Ok(candidate),
+            Err(error)
+                if error.kind() == std::io::ErrorKind::AddrInUse
+                    && candidate - port < fallbacks
+                    && candidate < u16::MAX =>
+            {
+                log::debug!("Callback port {} in use, trying the next port", candidate);
+                candidate += 1;
+            }
+            Err(error) => return Err(error),
+        }
+    }
+}
+
 pub fn load_client(port: u16) -> Result<Oauth2Client> {
     Ok(Oauth2Client::new(
         require_environment("TWITTER_OAUTH_CLIENT_ID")?,
@@ -24,26 +63,150 @@ pub fn load_client(port: u16) -> Result<Oauth2Client> {
     ))
 }
 
-pub fn login_start(client: &Oauth2Client) -> (url::Url, CsrfToken, PkceCodeVerifier) {
+/// Scopes requested at login. Without `like.read`, no likes can be archived.
+pub const SCOPES: [Scope; 3] = [Scope::TweetRead, Scope::UsersRead, Scope::LikeRead];
+
+/// Start a login. With `offline_access`, the token also includes a refresh token, so it
+/// can be renewed when it expires rather than logging in again.
+pub fn login_start(
+    client: &Oauth2Client,
+    offline_access: bool,
+) -> (url::Url, CsrfToken, PkceCodeVerifier) {
     // Create an OAuth2 client by specifying the client ID, client secret, authorization URL and
     // token URL.
 
     let (challenge, verifier) = PkceCodeChallenge::new_random_sha256();
     // create authorization url
-    let (url, state) = client.auth_url(
-        challenge,
-        [Scope::TweetRead, Scope::UsersRead, Scope::LikeRead],
-    );
+    let scopes = SCOPES
+        .into_iter()
+        .chain(offline_access.then_some(Scope::OfflineAccess));
+    let (url, state) = client.auth_url(challenge, scopes);
     // redirect user
     (url, state, verifier)
 }
 
+/// Exchange the code from the login callback for a token. Also returns the scopes
+/// granted, as read from the token response, which may be fewer than were requested.
 pub async fn login_end(
     client: &Oauth2Client,
     code: AuthorizationCode,
     verifier: PkceCodeVerifier,
-) -> twitter_v2::Result<Oauth2Token> {
+) -> twitter_v2::Result<(Oauth2Token, Vec<Scope>)> {
     // request oauth2 token
     let token = client.request_token(code, verifier).await?;
-    Ok(token)
+    let scopes = token.scopes().to_vec();
+    Ok((token, scopes))
+}
+
+/// Exchange the refresh token of an expired token for a new token.
+pub async fn refresh(
+    client: &Oauth2Client,
+    token: &Oauth2Token,
+) -> twitter_v2::Result<Oauth2Token> {
+    let refresh_token = token
+        .refresh_token()
+        .ok_or(twitter_v2::Error::NoRefreshToken)?;
+    client.refresh_token(refresh_token).await
+}
+
+/// Scopes requested at login, but not granted.
+pub fn missing_scopes(granted: &[Scope]) -> Vec<Scope> {
+    // Scopes can only be compared by name.
+    SCOPES
+        .into_iter()
+        .filter(|scope| {
+            !granted
+                .iter()
+                .any(|granted| granted.to_string() == scope.to_string())
+        })
+        .collect()
+}
+
+/// Revoke a token with Twitter, so it can no longer be used.
+pub async fn revoke(client: &Oauth2Client, token: &Oauth2Token) -> twitter_v2::Result<()> {
+    client.revoke_token(token.revokable_token()).await
+}
+
+/// Read a JSON file holding login secrets, if one exists.
+fn read_secret<T: DeserializeOwned>(path: &Path) -> Result<Option<T>> {
+    let contents = match std::fs::read(path) {
+        Ok(contents) => contents,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(source) => {
+            return Err(Error::TokenFile {
+                path: path.to_owned(),
+                source,
+            })
+        }
+    };
+    serde_json::from_slice(&contents)
+        .map(Some)
+        .map_err(|source| Error::TokenMalformed {
+            path: path.to_owned(),
+            source,
+        })
+}
+
+/// Write a JSON file holding login secrets. On unix, the file is only readable by the owner.
+fn write_secret<T: Serialize>(path: &Path, secret: &T) -> Result<()> {
+    let contents = serde_json::to_vec(secret).expect("secret is serializable");
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options
+        .open(path)
+        .and_then(|mut file| file.write_all(&contents))
+        .map_err(|source| Error::TokenFile {
+            path: path.to_owned(),
+            source,
+        })
+}
+
+/// Load a previously saved token, if one exists.
+pub fn load_token(path: &Path) -> Result<Option<Oauth2Token>> {
+    read_secret(path)
+}
+
+/// Save a token for reuse by later runs. On unix, the file is only readable by the owner.
+pub fn save_token(path: &Path, token: &Oauth2Token) -> Result<()> {
+    write_secret(path, token)
+}
+
+/// A login that has been started, but not finished.
+#[derive(Serialize, Deserialize)]
+pub struct PendingLogin {
+    /// Port of the callback url the login was started with.
+    pub port: u16,
+    pub state: CsrfToken,
+    pub verifier: PkceCodeVerifier,
+}
+
+/// Where a pending login is saved, beside the token file it will be saved to.
+pub fn pending_login_path(token_path: &Path) -> PathBuf {
+    let mut path = token_path.as_os_str().to_owned();
+    path.push(".pending");
+    PathBuf::from(path)
+}
+
+/// Load a login started by an earlier process, if there is one.
+pub fn load_pending_login(path: &Path) -> Result<Option<PendingLogin>> {
+    read_secret(path)
+}
+
+/// Save a started login, so another process can finish it.
+pub fn save_pending_login(path: &Path, pending: &PendingLogin) -> Result<()> {
+    write_secret(path, pending)
+}
+
+/// Remove a saved token. Returns `false` if there was no token to remove.
+pub fn remove_token(path: &Path) -> Result<bool> {
+    match std::fs::remove_file(path) {
+        Ok(()) => Ok(true),
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(source) => Err(Error::TokenFile {
+            path: path.to_owned(),
+            source,
+        }),
+    }
 }
@@ -1,22 +1,58 @@
 use anyhow::{Context, Result};
 use clap::Parser;
-use futures::{stream, StreamExt, TryStreamExt};
+use futures::{future, stream, StreamExt, TryStreamExt};
 use magpie_twitter_bot::{
+    api::TokenRefresh,
     auth,
-    bot::{Bot, ImageRef, Page},
-    download,
+    bot::{
+        self, AvatarRef, Bot, FetchOptions, ImageRef, ImageSize, OnMissingDate, Page,
+        ProcessOptions, ProcessOutcome,
+    },
+    bucket::{self, Bucket},
+    caption::{self, TcoLinks},
+    clock::{Clock, SystemClock},
+    concurrency::{AdaptiveLimit, Preset},
+    controls::{self, Controls, KeyReader},
+    download::{self, OnCollision, Redirects, Sink},
+    estimate::Estimate,
+    export::{self, JsonlWriter},
+    filter, gallery,
+    hook::Hook,
+    index::{self, Dedupe, Index},
+    lock::Lock,
+    manifest::{self, Checksum, Verified},
+    marker, metrics,
+    naming::{DateStyle, NameBuilder, Organize},
+    permalink::{self, Permalinks},
+    png, progress,
+    reencode::{self, Reencode},
+    summary::{ArchiveSummary, RunEvent},
+    video::{self, VideoQuality},
 };
-use std::path::PathBuf;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use reqwest::header::{HeaderName, HeaderValue};
+use reqwest::StatusCode;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::io::{IsTerminal, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::Mutex;
+use twitter_v2::authorization::{Oauth2Token, Scope};
+use twitter_v2::data::MediaType;
+use twitter_v2::id::NumericId;
+use twitter_v2::oauth2::AuthorizationCode;
 
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone)]
 #[command(author, version, about, long_about = None)]
 struct Args {
     /// Output directory to store files in.
-    #[arg(long)]
-    out_dir: PathBuf,
+    #[arg(
+        long,
+        required_unless_present_any = ["logout", "auth_start", "probe", "stdout", "fetch_to"]
+    )]
+    out_dir: Option<PathBuf>,
 
     /// Only do a sample of work.
     #[arg(long, default_value = "false")]
@@ -26,54 +62,776 @@ struct Args {
     #[arg(long, default_value = "49277")]
     port: u16,
 
-    /// Number of images to download in parallel.
-    #[arg(long, default_value = "8")]
-    download_n: usize,
-}
-
-fn arrow_spinner(message: &'static str) -> indicatif::ProgressBar {
-    let progress = indicatif::ProgressBar::new_spinner();
-    progress.set_style(
-        indicatif::ProgressStyle::with_template("{spinner:.blue} {msg}")
-            .expect("invalid progress template")
-            .tick_strings(&[
-                "▹▹▹▹▹",
-                "▸▹▹▹▹",
-                "▹▸▹▹▹",
-                "▹▹▸▹▹",
-                "▹▹▹▸▹",
-                "▹▹▹▹▸",
-                "▪▪▪▪▪",
-            ]),
-    );
-    progress.set_message(message);
-    progress.enable_steady_tick(std::time::Duration::from_millis(120));
-    progress
+    /// If --port is in use, try up to this many following ports for the OAuth callback.
+    /// Each port must be registered as a callback URI in the Twitter app settings.
+    #[arg(long, default_value = "0")]
+    port_fallback: u16,
+
+    /// Address the OAuth callback server listens on. Use with --no-open and port
+    /// forwarding to log in from another machine.
+    #[arg(long, default_value = "127.0.0.1")]
+    bind_host: IpAddr,
+
+    /// Print the login URL instead of opening it in a browser.
+    #[arg(long, default_value = "false")]
+    no_open: bool,
+
+    /// File to save the login token to, and reuse it from on later runs.
+    #[arg(long)]
+    token_file: Option<PathBuf>,
+
+    /// Request the offline.access scope at login, and refresh the login when it expires
+    /// mid-run rather than failing. Refreshed tokens are saved to --token-file.
+    #[arg(long, default_value = "false")]
+    offline_access: bool,
+
+    /// File listing the token files of several accounts, one per line. The likes of each
+    /// account are archived in turn, into a subdirectory of --out-dir named after its
+    /// token file.
+    #[arg(
+        long,
+        conflicts_with_all = ["token_file", "manifest", "verify", "auth_start", "auth_code"]
+    )]
+    accounts: Option<PathBuf>,
+
+    /// Revoke and delete the token saved in --token-file, then exit.
+    #[arg(long, default_value = "false", requires = "token_file")]
+    logout: bool,
+
+    /// Start a login without waiting for the callback: print the login URL, save the
+    /// login beside --token-file, then exit. Finish it with --auth-code and --auth-state.
+    #[arg(
+        long,
+        default_value = "false",
+        requires = "token_file",
+        conflicts_with = "logout"
+    )]
+    auth_start: bool,
+
+    /// Finish a login started with --auth-start, using the "code" parameter of the
+    /// callback URL the browser was redirected to.
+    #[arg(long, requires_all = ["auth_state", "token_file"])]
+    auth_code: Option<String>,
+
+    /// The "state" parameter of the callback URL, to check against the started login.
+    #[arg(long, requires = "auth_code")]
+    auth_state: Option<String>,
+
+    /// Log a line summarising download progress every this many seconds, such as for
+    /// long runs logged to a file.
+    #[arg(long)]
+    progress_interval_secs: Option<u64>,
+
+    /// Don't read keys to pause (space) and quit (q) while downloading in a terminal.
+    #[arg(long, default_value = "false")]
+    no_keyboard: bool,
+
+    /// Concurrency settings to use for any of --download-n, --prefetch-n,
+    /// --process-tweets-n and --user-lookup-n not given.
+    #[arg(long, value_enum, default_value_t)]
+    preset: Preset,
+
+    /// Number of images to download in parallel. Defaults to 8, or as set by --preset.
+    #[arg(long)]
+    download_n: Option<usize>,
+
+    // Delays each chunk of every download, to reproduce a slow network when working on
+    // throttling, progress and timeouts. Hidden, as it's only useful for development.
+    #[arg(long, hide = true)]
+    simulate_slow_ms: Option<u64>,
+
+    /// Upload media to an S3-compatible bucket, such as s3://bucket/prefix, rather than
+    /// the output directory. The output directory still holds the index and other state.
+    /// Credentials and region are read from the AWS_* environment variables.
+    #[arg(
+        long,
+        value_parser = bucket::parse_uri,
+        conflicts_with_all = [
+            "caption_sidecar",
+            "conditional",
+            "dedupe_across_runs",
+            "html_index",
+            "on_download",
+            "reencode",
+            "embed_png_metadata",
+            "verify",
+        ]
+    )]
+    s3_uri: Option<String>,
+
+    /// Write an index.html gallery of the downloaded media to the output directory.
+    #[arg(long, default_value = "false")]
+    html_index: bool,
+
+    /// Write every retry, rate limit wait, skip and failure of the run to this file, as a
+    /// JSON array.
+    #[arg(long, conflicts_with_all = ["logout", "verify"])]
+    events_json: Option<PathBuf>,
+
+    /// Print a JSON summary of the run to stdout when it finishes, even if it fails. Logs
+    /// and progress are written to stderr.
+    #[arg(long, default_value = "false", conflicts_with_all = ["logout", "verify"])]
+    report_json: bool,
+
+    /// Write Prometheus metrics for the run to this file when it finishes, for
+    /// node_exporter's textfile collector.
+    #[arg(long, conflicts_with_all = ["logout", "verify"])]
+    metrics_file: Option<PathBuf>,
+
+    /// Experimental: lower download concurrency while many downloads are failing, and
+    /// raise it back towards --download-n as they recover.
+    #[arg(long, default_value = "false")]
+    adaptive_concurrency: bool,
+
+    /// Size of photos to download. Smaller sizes save bandwidth.
+    #[arg(long, value_enum, default_value_t = ImageSize::Orig)]
+    image_size: ImageSize,
+
+    /// Remove query parameters from media urls before downloading, other than "format" and
+    /// "name" which select the media served.
+    #[arg(long, default_value = "false")]
+    strip_query: bool,
+
+    /// Maximum number of redirects to follow when downloading an image.
+    #[arg(long, default_value = "10")]
+    max_redirects: usize,
+
+    /// Fail downloads that redirect to a different host.
+    #[arg(long, default_value = "false")]
+    same_host_redirects_only: bool,
+
+    /// Extra header to send when downloading images, as "Name: Value". May be repeated.
+    #[arg(long, value_parser = download::parse_header)]
+    download_header: Vec<(HeaderName, HeaderValue)>,
+
+    /// Embed the author, a link to the tweet, its creation time and any alt text in each
+    /// downloaded PNG file, as JSON in an iTXt chunk with the keyword "Tweet".
+    #[arg(long, default_value = "false")]
+    embed_png_metadata: bool,
+
+    /// Write the tweet text and a link to the tweet next to each image, as <filename>.txt.
+    #[arg(long, default_value = "false")]
+    caption_sidecar: bool,
+
+    /// Remove t.co links from captions written with --caption-sidecar.
+    #[arg(long, default_value = "false", requires = "caption_sidecar")]
+    caption_strip_tco: bool,
+
+    /// Replace t.co links in captions written with --caption-sidecar with the urls they
+    /// point to.
+    #[arg(
+        long,
+        default_value = "false",
+        requires = "caption_sidecar",
+        conflicts_with = "caption_strip_tco"
+    )]
+    resolve_tco: bool,
+
+    /// What to do when a file already exists where an image would be downloaded to, but
+    /// wasn't downloaded from that image.
+    #[arg(long, value_enum, default_value_t = OnCollision::Rename)]
+    on_collision: OnCollision,
+
+    /// Command to run on each downloaded file. The file path is appended as an argument,
+    /// and tweet details are set in MAGPIE_* environment variables.
+    #[arg(long)]
+    on_download: Option<String>,
+
+    /// Number of --on-download commands to run in parallel.
+    #[arg(long, default_value = "4")]
+    on_download_n: usize,
+
+    /// Replace a stale lock held on the output directory by another run, such as one that
+    /// hung. Locks of runs that look active are never replaced.
+    #[arg(long, default_value = "false")]
+    force: bool,
+
+    /// Stop at the first failed download, cancelling any still in progress, instead of
+    /// attempting every image before reporting errors.
+    #[arg(long, default_value = "false")]
+    fail_fast: bool,
+
+    /// Look up the size of every image with a HEAD request before downloading.
+    #[arg(long, default_value = "false")]
+    prefetch_sizes: bool,
+
+    /// Number of HEAD requests to make in parallel when prefetching sizes. Defaults to
+    /// 32, or as set by --preset.
+    #[arg(long)]
+    prefetch_n: Option<usize>,
+
+    /// Write a manifest of downloaded files, with their checksums, to this path.
+    #[arg(long)]
+    manifest: Option<PathBuf>,
+
+    /// Write each manifest entry as its download completes, in download order, instead of
+    /// sorting them all at the end. Keeps memory bounded for very large archives.
+    #[arg(long, default_value = "false", requires = "manifest")]
+    stream_manifest: bool,
+
+    /// Record filenames in the manifest relative to the manifest's own directory, rather
+    /// than the output directory, so the manifest finds files wherever the two are moved
+    /// together. Pass it again when verifying.
+    #[arg(
+        long,
+        default_value = "false",
+        requires = "manifest",
+        conflicts_with = "s3_uri"
+    )]
+    manifest_relative: bool,
+
+    /// Include a link to each tweet in the manifest.
+    #[arg(long, default_value = "false", requires = "manifest")]
+    permalink_in_manifest: bool,
+
+    /// Link to tweets by id alone (https://twitter.com/i/status/<id>), so links keep
+    /// working if the author changes their username.
+    #[arg(long, default_value = "false")]
+    permalink_by_id: bool,
+
+    /// Host to use in links to tweets, such as x.com or a nitter instance.
+    #[arg(long, default_value = permalink::DEFAULT_HOST, value_parser = permalink::parse_host)]
+    permalink_host: String,
+
+    /// Log in, or load the saved token, then print the logged in user and granted scopes
+    /// and exit. Fails if any scope magpie needs wasn't granted.
+    #[arg(long, default_value = "false", conflicts_with_all = ["verify", "estimate", "accounts"])]
+    probe: bool,
+
+    /// Fetch liked tweets without downloading anything, and estimate the API requests
+    /// and rate limit waits an archive run would take, then exit.
+    #[arg(long, default_value = "false", conflicts_with_all = ["verify", "tweet_url", "accounts"])]
+    estimate: bool,
+
+    /// Check the files listed in --manifest against their recorded checksums, then exit.
+    #[arg(long, default_value = "false", requires = "manifest")]
+    verify: bool,
+
+    /// When verifying, download missing or corrupted files again.
+    #[arg(long, default_value = "false", requires = "verify")]
+    repair: bool,
+
+    /// Number of liked tweets to fetch per API request, from 10 to 100. Larger pages
+    /// mean fewer requests, but each response is larger. Defaults to the API's page size.
+    #[arg(long, value_parser = clap::value_parser!(u8).range(10..=100))]
+    page_size: Option<u8>,
+
+    /// Also download media from tweets quoted by liked tweets, attributed to the quoted
+    /// tweet's author. Costs one extra API request per page of likes.
+    #[arg(long, default_value = "false")]
+    include_quoted_media: bool,
+
+    /// Sort media into subdirectories, such as "type,author" for "photos/<username>/".
+    #[arg(long, value_delimiter = ',')]
+    organize_by: Vec<Organize>,
+
+    /// How to write each tweet's creation time in filenames.
+    #[arg(long, value_enum, default_value_t)]
+    date_style: DateStyle,
+
+    /// Lowercase usernames in filenames and directories.
+    #[arg(long, default_value = "false")]
+    lowercase_usernames: bool,
+
+    /// Trim whitespace from usernames in filenames and directories, and replace any
+    /// within them with "_".
+    #[arg(long, default_value = "false")]
+    trim_username_whitespace: bool,
+
+    /// With "--organize-by tweet", keep media from tweets with only one attached in the
+    /// parent directory, rather than in a directory of its own.
+    #[arg(long, default_value = "false", requires = "organize_by")]
+    flat_single_media: bool,
+
+    /// Also download the full size profile image of each author to "authors/<username>".
+    /// Requests one extra field in each author lookup.
+    #[arg(long, default_value = "false", conflicts_with = "no_username_cache")]
+    download_author_avatars: bool,
+
+    /// Only download media by authors not already in the output directory from earlier
+    /// runs, and list those authors.
+    #[arg(long, default_value = "false")]
+    only_new_authors: bool,
+
+    /// Only archive tweets liked since the last --incremental run into this output directory.
+    /// The first run archives everything.
+    #[arg(long, default_value = "false")]
+    incremental: bool,
+
+    /// Skip media from authors with fewer followers than this. A crude heuristic to
+    /// filter out spam and bot accounts, off by default.
+    #[arg(long, conflicts_with = "no_username_cache")]
+    min_author_followers: Option<usize>,
+
+    /// Don't look up or remember author usernames. Files are named by the author's
+    /// numeric id instead, saving one API request per author.
+    #[arg(long, default_value = "false")]
+    no_username_cache: bool,
+
+    /// Number of tweets in each page to extract media from in parallel. Mostly useful
+    /// with --no-username-cache off and many uncached authors. Defaults to 1, or as set
+    /// by --preset.
+    #[arg(long)]
+    process_tweets_n: Option<usize>,
+
+    /// Number of batches of up to 100 authors to look up in parallel, separately from
+    /// processing pages. Lower this to stay under the user lookup rate limit. Defaults
+    /// to 2, or as set by --preset.
+    #[arg(long)]
+    user_lookup_n: Option<usize>,
+
+    /// Archive these tweets, given as urls such as https://twitter.com/user/status/123,
+    /// rather than liked tweets.
+    #[arg(
+        long,
+        value_parser = permalink::parse_tweet_url,
+        conflicts_with_all = ["incremental", "start_token", "max_tweets_per_author", "accounts"]
+    )]
+    tweet_url: Vec<NumericId>,
+
+    /// Write the media of the tweet given with --tweet-url to stdout, rather than saving
+    /// it. Fails unless exactly one media item is found.
+    #[arg(
+        long,
+        default_value = "false",
+        requires = "tweet_url",
+        conflicts_with_all = ["out_dir", "report_json", "jsonl", "s3_uri", "accounts"]
+    )]
+    stdout: bool,
+
+    /// Find media without downloading it, and write it to this file, one JSON object per
+    /// line. Download it later with --download-from, without any API requests.
+    #[arg(
+        long,
+        conflicts_with_all = ["out_dir", "download_from", "incremental", "accounts", "stdout"]
+    )]
+    fetch_to: Option<PathBuf>,
+
+    /// Download media written by --fetch-to, rather than fetching tweets. Makes no API
+    /// requests, so expired urls can't be refreshed and author avatars aren't downloaded.
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "tweet_url",
+            "search",
+            "likes_of",
+            "start_token",
+            "incremental",
+            "accounts",
+            "stdout",
+            "jsonl",
+            "record_no_media",
+            "export_usernames",
+        ]
+    )]
+    download_from: Option<PathBuf>,
+
+    /// Pick which of the media found to download from a list in the terminal. Ignored
+    /// when not run in a terminal.
+    #[arg(long, default_value = "false")]
+    interactive: bool,
+
+    /// Archive the likes of this account, such as @magpie, rather than the logged in
+    /// user's. Only works where Twitter permits reading their likes.
+    #[arg(long, value_parser = parse_handle, conflicts_with_all = ["tweet_url", "accounts"])]
+    likes_of: Option<String>,
+
+    /// Archive recent tweets matching this search query, rather than liked tweets. Search
+    /// only covers the last 7 days, and needs Basic or higher Twitter API access.
+    #[arg(
+        long,
+        conflicts_with_all = ["tweet_url", "accounts", "likes_of", "incremental"]
+    )]
+    search: Option<String>,
+
+    /// Start fetching liked tweets from the page with this pagination token, rather than
+    /// the most recent likes. Tokens are logged with RUST_LOG=debug.
+    #[arg(long, value_parser = bot::parse_pagination_token)]
+    start_token: Option<String>,
+
+    /// Stop fetching liked tweets after this many seconds, and continue with the tweets
+    /// fetched so far.
+    #[arg(long)]
+    metadata_timeout_secs: Option<u64>,
+
+    /// Write a JSON object for each image to this file as it is found, one per line. Use
+    /// "-" for stdout.
+    #[arg(long)]
+    jsonl: Option<PathBuf>,
+
+    /// Write the ids and permalinks of liked tweets without photos to this file, one per
+    /// line.
+    #[arg(long)]
+    record_no_media: Option<PathBuf>,
+
+    /// Write the username of each author looked up this run to this file, by id. Written
+    /// as CSV if the path ends in ".csv", or JSON otherwise.
+    #[arg(long, conflicts_with = "no_username_cache")]
+    export_usernames: Option<PathBuf>,
+
+    /// Re-encode downloaded photos to save space, as webp or avif with an optional quality
+    /// from 0 to 100, such as "webp:75". The originals aren't kept. Avif needs the "avif"
+    /// build feature.
+    #[arg(long, value_parser = reencode::parse)]
+    reencode: Option<Reencode>,
+
+    /// Also download videos and animated GIFs.
+    #[arg(long, default_value = "false")]
+    include_videos: bool,
+
+    /// Which MP4 variant of each video to download: highest, lowest, or the highest
+    /// bitrate at or below a limit, such as "<=832k".
+    #[arg(long, default_value = "highest", value_parser = video::parse_quality, requires = "include_videos")]
+    video_quality: VideoQuality,
+
+    /// Download the HLS playlist of videos with no suitable MP4 variant.
+    #[arg(long, default_value = "false", requires = "include_videos")]
+    allow_hls: bool,
+
+    /// What to do with tweets the API returns without a creation date, such as withheld
+    /// tweets.
+    #[arg(long, value_enum, default_value_t = OnMissingDate::Epoch)]
+    on_missing_date: OnMissingDate,
+
+    /// Only download media from tweets whose ids don't appear in the names of files
+    /// under this directory, such as an archive saved by another tool, and list them.
+    #[arg(long)]
+    compare_with_dir: Option<PathBuf>,
+
+    /// With --compare-with-dir, only list the tweets with missing media, without
+    /// downloading anything.
+    #[arg(long, default_value = "false", requires = "compare_with_dir")]
+    list_missing_only: bool,
+
+    /// Skip media with these filename extensions, such as "png,gif". Applies to videos
+    /// too, such as "mp4", with --include-videos.
+    #[arg(long, value_delimiter = ',', value_parser = bot::parse_extension)]
+    exclude_ext: Vec<String>,
+
+    /// Skip tweets created more than this many days ago, and stop fetching liked tweets
+    /// after a page of only older tweets.
+    #[arg(long)]
+    max_age_days: Option<u32>,
+
+    /// Keep only this many of the most recent images from each author.
+    #[arg(long)]
+    max_per_author: Option<usize>,
+
+    /// Stop fetching liked tweets once this many have been fetched from each author seen.
+    /// Best effort, since likes aren't grouped by author. Combine with --max-per-author to
+    /// drop the extra images fetched.
+    #[arg(long)]
+    max_tweets_per_author: Option<usize>,
+
+    /// With --max-tweets-per-author, stop fetching once this many authors have reached
+    /// the cap.
+    #[arg(long, requires = "max_tweets_per_author")]
+    author_target: Option<usize>,
+
+    /// If a download fails because its url has expired, look up the tweet again for a
+    /// fresh url and retry once. Each retry costs an extra API request.
+    #[arg(long, default_value = "false")]
+    refresh_expired: bool,
+
+    /// Avoid storing the same content twice, across all runs into the output directory,
+    /// by deleting or hard-linking new files that duplicate an existing one.
+    #[arg(long, value_enum)]
+    dedupe_across_runs: Option<Dedupe>,
+
+    /// Skip images unchanged since a previous run, using the ETag/Last-Modified
+    /// headers recorded in the output directory index.
+    #[arg(long, default_value = "false")]
+    conditional: bool,
+}
+
+/// Parse a Twitter handle, with or without a leading `@`.
+fn parse_handle(handle: &str) -> std::result::Result<String, String> {
+    let username = handle.strip_prefix('@').unwrap_or(handle);
+    let valid = !username.is_empty()
+        && username
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if valid {
+        Ok(username.to_owned())
+    } else {
+        Err(format!("expected a handle such as @magpie, got '{handle}'"))
+    }
+}
+
+impl Args {
+    fn download_n(&self) -> usize {
+        self.download_n
+            .unwrap_or_else(|| self.preset.tuning().download_n)
+    }
+
+    fn prefetch_n(&self) -> usize {
+        self.prefetch_n
+            .unwrap_or_else(|| self.preset.tuning().prefetch_n)
+    }
+
+    fn process_tweets_n(&self) -> usize {
+        self.process_tweets_n
+            .unwrap_or_else(|| self.preset.tuning().process_tweets_n)
+    }
+
+    fn user_lookup_n(&self) -> usize {
+        self.user_lookup_n
+            .unwrap_or_else(|| self.preset.tuning().user_lookup_n)
+    }
 }
 
-async fn run(args: &Args) -> Result<()> {
+async fn login(args: &Args) -> Result<Oauth2Token> {
+    if let (Some(code), Some(state)) = (&args.auth_code, &args.auth_state) {
+        return finish_login(args, code, state).await;
+    }
+    if let Some(token_file) = &args.token_file {
+        if let Some(token) = auth::load_token(token_file)? {
+            if !token.is_expired() {
+                log::info!("Using saved token from '{}'", token_file.display());
+                return Ok(token);
+            }
+            log::info!("Saved token has expired");
+            if token.refresh_token().is_some() {
+                let oauth2_client =
+                    auth::load_client(args.port).context("Loading OAuth2 configuration")?;
+                match auth::refresh(&oauth2_client, &token).await {
+                    Ok(token) => {
+                        log::info!("Refreshed saved token");
+                        auth::save_token(token_file, &token)?;
+                        return Ok(token);
+                    }
+                    Err(error) => log::warn!("Failed to refresh saved token: {error}"),
+                }
+            }
+        }
+    }
+
     log::info!("Logging into Twitter with OAuth");
-    let oauth2_client = auth::load_client(args.port).context("Loading OAuth2 configuration")?;
-    let (url, state, verifier) = auth::login_start(&oauth2_client);
+    let port = auth::find_callback_port(args.bind_host, args.port, args.port_fallback)
+        .with_context(|| format!("Failed to listen for login callback on port {}", args.port))?;
+    let oauth2_client = auth::load_client(port).context("Loading OAuth2 configuration")?;
+    let (url, state, verifier) = auth::login_start(&oauth2_client, args.offline_access);
 
-    open::that(url.to_string()).context("Failed to start login flow")?;
-    let address = std::net::SocketAddr::from(([127, 0, 0, 1], args.port));
+    if args.no_open {
+        eprintln!("Open the following URL in a browser to log in:\n\n    {url}\n");
+    } else {
+        open::that(url.to_string()).context("Failed to start login flow")?;
+    }
+    let address = std::net::SocketAddr::from((args.bind_host, port));
     log::debug!("Waiting for callback...");
-    let params = oneshot_oauth2_callback::oneshot(&address)
+    // The listener runs in its own task, so a panic while handling the callback fails
+    // the login with an error rather than aborting.
+    let params = tokio::spawn(async move { oneshot_oauth2_callback::oneshot(&address).await })
         .await
-        .context("Login error")?;
+        .map_err(|error| anyhow::anyhow!("Login flow did not complete: {error}"))?
+        .map_err(|error| match error {
+            oneshot_oauth2_callback::Error::NoResponse => {
+                anyhow::anyhow!("Login flow did not complete, as no callback was received")
+            }
+            error => anyhow::Error::new(error).context("Login error"),
+        })?;
     assert_eq!(state.secret(), params.state.secret());
-    let access_token = auth::login_end(&oauth2_client, params.code, verifier)
+    let (access_token, scopes) = auth::login_end(&oauth2_client, params.code, verifier)
         .await
         .context("Failed to fetch access token")?;
+    warn_missing_scopes(&scopes);
+    if let Some(token_file) = &args.token_file {
+        auth::save_token(token_file, &access_token)?;
+    }
+    Ok(access_token)
+}
+
+/// A bot using the login, which refreshes it mid-run with --offline-access.
+fn bot(args: &Args, access_token: Oauth2Token) -> Result<Bot> {
+    let mut builder = Bot::builder(access_token);
+    if args.offline_access {
+        builder = builder.refresh(TokenRefresh {
+            client: auth::load_client(args.port).context("Loading OAuth2 configuration")?,
+            token_file: args.token_file.clone(),
+        });
+    }
+    Ok(builder.build()?)
+}
+
+/// Warn if login granted fewer scopes than requested, as the run may then find nothing.
+fn warn_missing_scopes(granted: &[Scope]) {
+    let missing = auth::missing_scopes(granted);
+    if !missing.is_empty() {
+        let missing: Vec<String> = missing.iter().map(ToString::to_string).collect();
+        log::warn!(
+            "Login didn't grant the {} scopes, so liked tweets may not be readable",
+            missing.join(", ")
+        );
+    }
+}
+
+/// Start a login to be finished by a later run with --auth-code and --auth-state.
+fn start_login(args: &Args) -> Result<()> {
+    let token_file = args.token_file.as_deref().expect("required by clap");
+    let oauth2_client = auth::load_client(args.port).context("Loading OAuth2 configuration")?;
+    let (url, state, verifier) = auth::login_start(&oauth2_client, args.offline_access);
+    auth::save_pending_login(
+        &auth::pending_login_path(token_file),
+        &auth::PendingLogin {
+            port: args.port,
+            state,
+            verifier,
+        },
+    )?;
+    eprintln!(
+        "Open the following URL in a browser to log in:\n\n    {url}\n\n\
+         Then run again with the code and state parameters from the URL you are redirected to."
+    );
+    Ok(())
+}
+
+/// Finish a login started by --auth-start, without running the callback server.
+async fn finish_login(args: &Args, code: &str, state: &str) -> Result<Oauth2Token> {
+    let token_file = args.token_file.as_deref().expect("required by clap");
+    let pending_path = auth::pending_login_path(token_file);
+    let pending =
+        auth::load_pending_login(&pending_path)?.ok_or_else(|| auth::Error::LoginNotStarted {
+            path: token_file.to_owned(),
+        })?;
+    if pending.state.secret() != state {
+        anyhow::bail!("Login state does not match the started login");
+    }
+    let oauth2_client = auth::load_client(pending.port).context("Loading OAuth2 configuration")?;
+    let (access_token, scopes) = auth::login_end(
+        &oauth2_client,
+        AuthorizationCode::new(code.to_owned()),
+        pending.verifier,
+    )
+    .await
+    .context("Failed to fetch access token")?;
+    warn_missing_scopes(&scopes);
+    auth::save_token(token_file, &access_token)?;
+    auth::remove_token(&pending_path)?;
+    Ok(access_token)
+}
 
-    let bot = std::sync::Arc::new(Bot::new(access_token));
+async fn logout(args: &Args) -> Result<()> {
+    let token_file = args.token_file.as_deref().expect("required by clap");
+    match auth::load_token(token_file) {
+        Ok(Some(token)) if token.is_expired() => {
+            log::info!("Saved token has already expired");
+        }
+        Ok(Some(token)) => {
+            let oauth2_client =
+                auth::load_client(args.port).context("Loading OAuth2 configuration")?;
+            match auth::revoke(&oauth2_client, &token).await {
+                Ok(()) => log::info!("Revoked token with Twitter"),
+                Err(error) => {
+                    log::warn!("Failed to revoke token, it may already be invalid: {error}")
+                }
+            }
+        }
+        Ok(None) => {}
+        Err(error) => log::warn!("{error}"),
+    }
+    if auth::remove_token(token_file)? {
+        log::info!("Removed saved token '{}'", token_file.display());
+    } else {
+        log::info!("No saved token at '{}'", token_file.display());
+    }
+    Ok(())
+}
+
+/// How media files are named, from the naming options.
+fn names(args: &Args) -> NameBuilder {
+    NameBuilder::new()
+        .organize_by(args.organize_by.clone())
+        .flat_single_media(args.flat_single_media)
+        .date_style(args.date_style)
+        .lowercase_usernames(args.lowercase_usernames)
+        .normalize_whitespace(args.trim_username_whitespace)
+}
+
+/// Client for downloading media, from the command line.
+fn download_client(args: &Args) -> download::Client {
+    let client = download::client(
+        args.download_header.clone(),
+        Redirects {
+            max: args.max_redirects,
+            same_host_only: args.same_host_redirects_only,
+        },
+    );
+    match args.simulate_slow_ms {
+        Some(delay) => {
+            log::warn!("Simulating a slow network, delaying each download chunk by {delay}ms");
+            client.simulate_slow(Duration::from_millis(delay))
+        }
+        None => client,
+    }
+}
+
+/// Earliest creation time of tweets to archive, given --max-age-days.
+fn created_after(args: &Args, clock: &dyn Clock) -> Option<time::OffsetDateTime> {
+    args.max_age_days
+        .map(|days| clock.now() - time::Duration::days(i64::from(days)))
+}
+
+/// How tweets are processed into media, from the command line.
+fn process_options(
+    args: &Args,
+    since: Option<NumericId>,
+    created_after: Option<time::OffsetDateTime>,
+) -> ProcessOptions {
+    ProcessOptions {
+        include_quoted_media: args.include_quoted_media,
+        author_ids_as_names: args.no_username_cache,
+        author_avatars: args.download_author_avatars,
+        stop_at: since,
+        on_missing_date: args.on_missing_date,
+        video_quality: args.include_videos.then_some(args.video_quality),
+        allow_hls: args.allow_hls,
+        image_size: args.image_size,
+        min_author_followers: args.min_author_followers,
+        created_after,
+        tweet_concurrency: args.process_tweets_n(),
+        exclude_extensions: args.exclude_ext.clone(),
+    }
+}
 
+/// Fetch pages of liked tweets. Also returns the most recently liked tweet, if the
+/// `--incremental` marker can be moved on to it.
+async fn fetch_liked_pages(
+    args: &Args,
+    bot: &Bot,
+    since: Option<NumericId>,
+    created_after: Option<time::OffsetDateTime>,
+) -> Result<(Vec<Page>, Option<NumericId>)> {
     log::info!("Fetching liked tweet data");
-    let progress = Arc::new(Mutex::new(arrow_spinner("Fetching tweets...")));
+    let progress = Arc::new(Mutex::new(progress::arrow_spinner(
+        "Fetching tweets...",
+        progress::DEFAULT_TICK,
+    )));
     let metadata_page_count: Arc<AtomicUsize> = Default::default();
-    let image_ref_pages: Vec<Page> = bot
-        .fetch_liked_tweets()
+    let timed_out = AtomicBool::new(false);
+    let deadline = async {
+        match args.metadata_timeout_secs {
+            Some(secs) => {
+                tokio::time::sleep(Duration::from_secs(secs)).await;
+                timed_out.store(true, Ordering::SeqCst);
+            }
+            None => futures::future::pending().await,
+        }
+    };
+    let options = FetchOptions {
+        page_size: args.page_size.map(usize::from),
+        stop_at: since,
+        start_token: args.start_token.clone(),
+        author_sample: args
+            .max_tweets_per_author
+            .map(|max| bot::AuthorSample::new(max, args.author_target)),
+        created_after,
+        likes_of: args.likes_of.clone(),
+    };
+    let pages = match &args.search {
+        Some(query) => future::Either::Left(bot.fetch_search_tweets(query, options)),
+        None => future::Either::Right(bot.fetch_liked_tweets(options)),
+    };
+    let image_ref_pages: Vec<Page> = pages
         .filter_map(|page| {
             let metadata_page_count = metadata_page_count.clone();
             let progress = progress.clone();
@@ -86,80 +844,1320 @@ async fn run(args: &Args) -> Result<()> {
                 Some(page)
             }
         })
+        .take_until(deadline)
         .try_collect()
         .await?;
     progress.lock().await.finish_and_clear();
+    // Likes older than those fetched haven't been seen, so a timed out run can't move the
+    // incremental marker on. Neither can a run that skipped the newest likes, or one that
+    // may have stopped early with a sample of each author or at old tweets.
+    let newest_liked = if args.start_token.is_some()
+        || args.max_tweets_per_author.is_some()
+        || args.max_age_days.is_some()
+    {
+        None
+    } else if timed_out.load(Ordering::SeqCst) {
+        log::warn!(
+            "Timed out fetching liked tweets, continuing with the {} pages fetched",
+            image_ref_pages.len()
+        );
+        None
+    } else {
+        image_ref_pages
+            .first()
+            .and_then(|page| page.data())
+            .and_then(|tweets| tweets.first())
+            .map(|tweet| tweet.id)
+    };
+    Ok((image_ref_pages, newest_liked))
+}
+
+/// Media found to download, before filtering against the output directory.
+struct Found {
+    /// The bot that found the media, unless it was read from a file.
+    bot: Option<Arc<Bot>>,
+    image_refs: Vec<ImageRef>,
+    avatars: BTreeMap<NumericId, AvatarRef>,
+    /// The newest liked tweet seen, for --incremental.
+    newest_liked: Option<NumericId>,
+}
+
+/// Log in, then fetch and process tweets into the media to download.
+async fn find_media(
+    args: &Args,
+    since: Option<NumericId>,
+    created_after: Option<time::OffsetDateTime>,
+    summary: &mut ArchiveSummary,
+) -> Result<Found> {
+    let access_token = login(args).await?;
+    let bot = std::sync::Arc::new(bot(args, access_token)?);
+
+    let (image_ref_pages, newest_liked) = if args.tweet_url.is_empty() {
+        fetch_liked_pages(args, &bot, since, created_after).await?
+    } else {
+        (Vec::new(), None)
+    };
 
     log::info!("Enriching {} pages with other data", image_ref_pages.len());
-    let progress = arrow_spinner("Processing tweets...");
+    let progress = progress::arrow_spinner("Processing tweets...", progress::DEFAULT_TICK);
+    let options = process_options(args, since, created_after);
+    bot.resolve_authors(&image_ref_pages, &options, args.user_lookup_n())
+        .await
+        .context("Failed to look up authors")?;
     let mut join_set = tokio::task::JoinSet::new();
     for page in image_ref_pages.into_iter() {
         let bot = bot.clone();
-        join_set.spawn(async move {
-            let page = bot.process_page(&page).await;
-            page
-        });
+        let options = options.clone();
+        join_set.spawn(async move { bot.process_page(&page, &options).await });
+    }
+    if !args.tweet_url.is_empty() {
+        log::info!("Fetching {} tweets", args.tweet_url.len());
+        let bot = bot.clone();
+        let ids = args.tweet_url.clone();
+        join_set.spawn(async move { bot.process_tweets(&ids, &options).await });
     }
 
-    let mut image_refs: Vec<ImageRef> = Vec::new();
+    let mut jsonl = match &args.jsonl {
+        Some(path) => Some(
+            JsonlWriter::create(path, names(args))
+                .with_context(|| format!("Failed to create '{}'", path.display()))?,
+        ),
+        None => None,
+    };
+    let mut outcome = ProcessOutcome::default();
     while let Some(result) = join_set.join_next().await {
         let page = result
             .context("failed to join future")?
             .context("Failed to fetch image metadata")?;
-        image_refs.extend(page);
+        if let Some(jsonl) = jsonl.as_mut() {
+            for image_ref in page.image_refs.iter() {
+                jsonl
+                    .write(image_ref)
+                    .context("Failed to write JSONL record")?;
+            }
+        }
+        outcome.merge(page);
         progress.set_message(format!(
             "Processing tweets... found {} images",
-            image_refs.len()
+            outcome.image_refs.len()
         ));
     }
     progress.finish_and_clear();
+    summary.found = outcome.image_refs.len();
+    summary.skipped_no_url = outcome.skipped_no_url;
+    summary.invalid_url = outcome.invalid_url;
+    summary.missing_date = outcome.missing_date;
+    summary.excluded_by_extension = outcome.excluded_by_extension;
+    summary.sensitive = outcome.sensitive;
+    summary.missing_media = outcome.missing_media;
+    summary.events.append(&mut outcome.events);
+    if let Some(path) = &args.export_usernames {
+        let usernames = bot.usernames().await;
+        export::write_usernames(path, &usernames)
+            .with_context(|| format!("Failed writing '{}'", path.display()))?;
+        summary.exported_usernames = usernames.len();
+    }
+    if let Some(path) = &args.record_no_media {
+        let permalinks = Permalinks {
+            host: args.permalink_host.clone(),
+            ..Default::default()
+        };
+        let mut no_media = outcome.no_media.clone();
+        no_media.sort();
+        let contents: String = no_media
+            .into_iter()
+            .map(|id| format!("{} {}\n", id, permalinks.link_by_id(id)))
+            .collect();
+        std::fs::write(path, contents)
+            .with_context(|| format!("Failed writing '{}'", path.display()))?;
+    }
+    let mut image_refs = outcome.image_refs;
+    if args.strip_query {
+        for image_ref in image_refs.iter_mut() {
+            download::strip_query(&mut image_ref.url);
+        }
+    }
+    if let Some(max_per_author) = args.max_per_author {
+        let (kept, dropped) = filter::cap_per_author(image_refs, max_per_author);
+        image_refs = kept;
+        summary.dropped_per_author = dropped;
+    }
+    Ok(Found {
+        bot: Some(bot),
+        image_refs,
+        avatars: outcome.avatars,
+        newest_liked,
+    })
+}
+
+/// Find media without downloading it, and write it to a file for --download-from.
+async fn fetch_to(args: &Args, path: &Path, summary: &mut ArchiveSummary) -> Result<()> {
+    let created_after = created_after(args, &SystemClock);
+    let found = find_media(args, None, created_after, summary).await?;
+    export::write_image_refs(path, &found.image_refs)
+        .with_context(|| format!("Failed writing '{}'", path.display()))?;
+    log::info!(
+        "Wrote {} images to '{}'",
+        found.image_refs.len(),
+        path.display()
+    );
+    summary.log();
+    Ok(())
+}
+
+async fn run(args: &Args, summary: &mut ArchiveSummary) -> Result<()> {
+    if args.report_json && args.jsonl.as_deref() == Some(Path::new("-")) {
+        anyhow::bail!("--jsonl can't write to stdout with --report-json");
+    }
+    let out_dir = args.out_dir.as_deref().expect("required by clap");
+    // Lock as early as possible. If the output directory doesn't exist yet, it is locked
+    // once created, so that runs finding nothing to download don't create it.
+    let mut lock = if out_dir.exists() {
+        Some(Lock::acquire(out_dir, args.force)?)
+    } else {
+        None
+    };
+    let sink = match &args.s3_uri {
+        Some(uri) => Sink::Bucket(Box::new(Bucket::from_uri(uri)?)),
+        None => Sink::Local,
+    };
+    let since = if args.incremental {
+        marker::load(out_dir)?
+    } else {
+        None
+    };
+    if let Some(since) = since {
+        log::info!("Archiving tweets liked since tweet {since}");
+    }
+    let created_after = created_after(args, &SystemClock);
+    let Found {
+        bot,
+        mut image_refs,
+        avatars: outcome_avatars,
+        newest_liked,
+    } = match &args.download_from {
+        Some(path) => {
+            let image_refs = export::read_image_refs(path)
+                .with_context(|| format!("Failed to read '{}'", path.display()))?;
+            log::info!("Read {} images from '{}'", image_refs.len(), path.display());
+            summary.found = image_refs.len();
+            Found {
+                bot: None,
+                image_refs,
+                avatars: BTreeMap::new(),
+                newest_liked: None,
+            }
+        }
+        None => find_media(args, since, created_after, summary).await?,
+    };
+
+    if let Some(dir) = &args.compare_with_dir {
+        let existing = filter::tweet_ids_in_dir(dir)
+            .with_context(|| format!("Failed reading '{}'", dir.display()))?;
+        let found = image_refs.len();
+        image_refs.retain(|image_ref| !existing.contains(&image_ref.tweet.id));
+        let permalinks = Permalinks {
+            host: args.permalink_host.clone(),
+            ..Default::default()
+        };
+        let missing: BTreeMap<NumericId, String> = image_refs
+            .iter()
+            .map(|image_ref| (image_ref.tweet.id, permalinks.link(&image_ref.tweet)))
+            .collect();
+        log::info!(
+            "{} of {} images, from {} tweets, are missing from '{}'",
+            image_refs.len(),
+            found,
+            missing.len(),
+            dir.display()
+        );
+        for permalink in missing.values() {
+            log::info!("  {permalink}");
+        }
+        if args.list_missing_only {
+            return Ok(());
+        }
+    }
+
+    let index = Index::load(out_dir)?;
+    if args.only_new_authors {
+        image_refs.retain(|image_ref| !index.has_author(&image_ref.tweet.username));
+        let new_authors: BTreeSet<&str> = image_refs
+            .iter()
+            .map(|image_ref| image_ref.tweet.username.as_str())
+            .collect();
+        log::info!(
+            "Found {} new authors: {}",
+            new_authors.len(),
+            new_authors.into_iter().collect::<Vec<_>>().join(", ")
+        );
+    }
+
+    if args.interactive {
+        image_refs = select_interactively(image_refs)?;
+    }
+
+    if image_refs.is_empty() {
+        log::info!("No media found for the selected source/filters");
+        return save_marker(args, out_dir, newest_liked);
+    }
+
+    let client = download_client(args);
+    if args.prefetch_sizes {
+        let progress = progress::arrow_spinner("Fetching image sizes...", progress::DEFAULT_TICK);
+        let sizes: Vec<Option<u64>> = stream::iter(&image_refs)
+            .map(|image_ref| download::content_length(&client, image_ref.url.clone()))
+            .buffer_unordered(args.prefetch_n())
+            .collect()
+            .await;
+        progress.finish_and_clear();
+        let total: u64 = sizes.iter().flatten().sum();
+        let unknown = sizes.iter().filter(|size| size.is_none()).count();
+        log::info!(
+            "Expecting to download {} ({} images of unknown size)",
+            indicatif::HumanBytes(total),
+            unknown
+        );
+    }
 
     log::info!("Downloading {} images", image_refs.len());
-    std::fs::create_dir_all(&args.out_dir).with_context(|| {
-        format!(
-            "Failed to create output directory '{}'",
-            args.out_dir.display()
+    std::fs::create_dir_all(out_dir)
+        .with_context(|| format!("Failed to create output directory '{}'", out_dir.display()))?;
+    if lock.is_none() {
+        lock = Some(Lock::acquire(out_dir, args.force)?);
+    }
+    let downloader = Downloader {
+        args,
+        permalinks: Permalinks {
+            host: args.permalink_host.clone(),
+            // Without usernames, only id based permalinks resolve.
+            by_id: args.permalink_by_id || args.no_username_cache,
+        },
+        names: names(args),
+        out_dir,
+        bot: bot.as_deref(),
+        client,
+        sink,
+        index: std::sync::Mutex::new(index),
+        manifest_entries: Default::default(),
+        manifest_prefix: match &args.manifest {
+            Some(path) if args.manifest_relative => {
+                Some(manifest::relative_prefix(path, out_dir).with_context(|| {
+                    format!("Failed to locate '{}' from the manifest", out_dir.display())
+                })?)
+            }
+            _ => None,
+        },
+        manifest_writer: match &args.manifest {
+            Some(path) if args.stream_manifest => {
+                Some(std::sync::Mutex::new(manifest::StreamWriter::create(path)?))
+            }
+            _ => None,
+        },
+        claimed: Default::default(),
+        hook: args
+            .on_download
+            .clone()
+            .map(|command| Hook::new(command, args.on_download_n)),
+        adaptive_limit: args
+            .adaptive_concurrency
+            .then(|| AdaptiveLimit::new(args.download_n())),
+        progress: progress::bar(
+            image_refs.len().try_into().expect("usize in u64"),
+            "Downloaded",
+        ),
+        downloaded_count: Default::default(),
+        downloaded_bytes: Default::default(),
+        not_modified_count: Default::default(),
+        duplicate_count: Default::default(),
+        collision_count: Default::default(),
+        hook_failure_count: Default::default(),
+        failed_count: Default::default(),
+        events: Default::default(),
+        started: std::time::Instant::now(),
+    };
+    let (key_reader, controls) = start_key_reader(args);
+    // Images are only taken once downloads are resumed, and none are taken after quitting.
+    let images = {
+        let controls = &controls;
+        stream::iter(image_refs)
+            .then(move |image_ref| async move { controls.proceed().await.then_some(image_ref) })
+            .take_while(|image_ref| future::ready(image_ref.is_some()))
+            .filter_map(future::ready)
+    };
+    let downloads = async {
+        if args.fail_fast {
+            // Dropping the remaining futures on the first error cancels in-flight downloads.
+            // Files are only renamed into place once a response body is complete, so none are
+            // left partial.
+            let result = images
+                .map(Ok)
+                .try_for_each_concurrent(args.download_n(), |image_ref| {
+                    downloader.download(image_ref)
+                })
+                .await;
+            if let Err(error) = &result {
+                summary.errors.push(format!("{error:#}"));
+            }
+            result
+        } else {
+            let results: Vec<Result<()>> = images
+                .map(|image_ref| downloader.download(image_ref))
+                .buffer_unordered(args.download_n())
+                .collect()
+                .await;
+            summary.errors = results
+                .iter()
+                .filter_map(|result| result.as_ref().err())
+                .map(|error| format!("{error:#}"))
+                .collect();
+            results.into_iter().collect::<Result<()>>()
+        }
+    };
+    let result = match args.progress_interval_secs {
+        // Status logging stops when downloads finish, as its future is dropped.
+        Some(secs) => tokio::select! {
+            result = downloads => result,
+            _ = downloader.log_status(Duration::from_secs(secs)) => {
+                unreachable!("status logging never finishes")
+            }
+        },
+        None => downloads.await,
+    };
+    drop(key_reader);
+    let result = match result {
+        Ok(()) if controls.quit() => Err(anyhow::anyhow!("Quit before all images were downloaded")),
+        result => result,
+    };
+    if !outcome_avatars.is_empty() {
+        summary.avatars =
+            download_avatars(args, &downloader.client, out_dir, outcome_avatars).await;
+    }
+    downloader.finish(summary)?;
+    // Only move the marker on once everything is archived, so failures are retried.
+    if result.is_ok() {
+        save_marker(args, out_dir, newest_liked)?;
+    }
+    drop(lock);
+    if let Some(bot) = &bot {
+        summary.events.extend(
+            bot.rate_limit_pauses()
+                .await
+                .into_iter()
+                .map(|until| RunEvent::RateLimitWait { until }),
+        );
+    }
+    summary.log();
+    result
+}
+
+/// Write the only media item of the chosen tweets to stdout. Logs and progress go to
+/// stderr, so stdout holds nothing but the file.
+async fn write_stdout(args: &Args) -> Result<()> {
+    let access_token = login(args).await?;
+    let bot = bot(args, access_token)?;
+    let options = ProcessOptions {
+        author_avatars: false,
+        ..process_options(args, None, created_after(args, &SystemClock))
+    };
+    let outcome = bot
+        .process_tweets(&args.tweet_url, &options)
+        .await
+        .context("Failed to fetch image metadata")?;
+    let image_ref = match outcome.image_refs.as_slice() {
+        [image_ref] => image_ref,
+        image_refs => anyhow::bail!(
+            "--stdout needs exactly one media item, but found {}",
+            image_refs.len()
+        ),
+    };
+    let mut url = image_ref.url.clone();
+    if args.strip_query {
+        download::strip_query(&mut url);
+    }
+    let client = download_client(args);
+    download::media(&client, url, &download::Stdout, "-")
+        .await
+        .with_context(|| format!("Failed to download '{}'", image_ref.url))?;
+    Ok(())
+}
+
+/// Start reading keys to pause and quit downloads, if running in a terminal.
+fn start_key_reader(args: &Args) -> (Option<KeyReader>, Controls) {
+    if args.no_keyboard || !progress::is_interactive() || !std::io::stdin().is_terminal() {
+        return (None, Controls::fixed());
+    }
+    match KeyReader::start() {
+        Ok((key_reader, controls)) => {
+            log::info!("Press space to pause or resume downloads, or q to quit");
+            (Some(key_reader), controls)
+        }
+        Err(error) => {
+            log::warn!("Keyboard controls are unavailable: {error}");
+            (None, Controls::fixed())
+        }
+    }
+}
+
+/// Characters of tweet text shown beside each media when selecting interactively.
+const SELECTION_SNIPPET_CHARS: usize = 60;
+
+/// Let the user pick which media to download from a list in the terminal. Outside a
+/// terminal, everything found is downloaded.
+fn select_interactively(image_refs: Vec<ImageRef>) -> Result<Vec<ImageRef>> {
+    if image_refs.is_empty() {
+        return Ok(image_refs);
+    }
+    if !progress::is_interactive() || !std::io::stdin().is_terminal() {
+        log::warn!("Not running in a terminal, so downloading all media found");
+        return Ok(image_refs);
+    }
+    let items: Vec<String> = image_refs
+        .iter()
+        .map(|image_ref| {
+            let text: String = image_ref
+                .tweet
+                .text
+                .split_whitespace()
+                .collect::<Vec<_>>()
+                .join(" ");
+            let mut snippet: String = text.chars().take(SELECTION_SNIPPET_CHARS).collect();
+            if snippet.len() < text.len() {
+                snippet.push('…');
+            }
+            format!(
+                "{} @{}: {}",
+                image_ref.media_key, image_ref.tweet.username, snippet
+            )
+        })
+        .collect();
+    let chosen = dialoguer::MultiSelect::new()
+        .with_prompt("Choose media to download (space to toggle, enter to confirm)")
+        .items(&items)
+        .interact_opt()
+        .context("Failed to read selection")?
+        .ok_or_else(|| anyhow::anyhow!("Selection was cancelled"))?;
+    log::info!("Selected {} of {} media", chosen.len(), image_refs.len());
+    let chosen: HashSet<usize> = chosen.into_iter().collect();
+    Ok(image_refs
+        .into_iter()
+        .enumerate()
+        .filter(|(index, _)| chosen.contains(index))
+        .map(|(_, image_ref)| image_ref)
+        .collect())
+}
+
+/// Archive the likes of each account listed in an accounts file, one after another.
+async fn run_accounts(args: &Args, accounts: &Path, total: &mut ArchiveSummary) -> Result<()> {
+    let out_dir = args.out_dir.as_deref().expect("required by clap");
+    let contents = std::fs::read_to_string(accounts)
+        .with_context(|| format!("Failed to read accounts '{}'", accounts.display()))?;
+    let token_files: Vec<PathBuf> = contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(PathBuf::from)
+        .collect();
+
+    let mut failed = Vec::new();
+    for token_file in token_files.iter() {
+        let name = token_file
+            .file_stem()
+            .with_context(|| format!("Invalid token file '{}'", token_file.display()))?
+            .to_string_lossy()
+            .into_owned();
+        log::info!("Archiving likes of account '{name}'");
+        let account_args = Args {
+            out_dir: Some(out_dir.join(&name)),
+            token_file: Some(token_file.clone()),
+            accounts: None,
+            ..args.clone()
+        };
+        let mut summary = ArchiveSummary::default();
+        if let Err(error) = run(&account_args, &mut summary).await {
+            log::error!("Failed to archive account '{name}': {error:#}");
+            failed.push(name);
+        }
+        total.merge(summary);
+    }
+
+    log::info!("Total across {} accounts:", token_files.len());
+    total.log();
+    if !failed.is_empty() {
+        anyhow::bail!("Failed to archive accounts: {}", failed.join(", "));
+    }
+    Ok(())
+}
+
+/// Download the profile image of each author, returning how many were written.
+async fn download_avatars(
+    args: &Args,
+    client: &download::Client,
+    out_dir: &Path,
+    avatars: BTreeMap<NumericId, AvatarRef>,
+) -> usize {
+    log::info!("Downloading {} author avatars", avatars.len());
+    let results: Vec<bool> = stream::iter(avatars.into_values())
+        .map(|avatar| async move {
+            let path = out_dir.join(avatar.filename());
+            let result = match path.parent().map(std::fs::create_dir_all) {
+                Some(Err(error)) => Err(error).context("Failed to create directory"),
+                _ => download::media(client, avatar.url.clone(), out_dir, &avatar.filename())
+                    .await
+                    .context("Failed to download"),
+            };
+            if let Err(error) = &result {
+                log::warn!("Avatar of '{}': {:#}", avatar.username, error);
+            }
+            result.is_ok()
+        })
+        .buffer_unordered(args.download_n())
+        .collect()
+        .await;
+    results.into_iter().filter(|ok| *ok).count()
+}
+
+/// Record the newest liked tweet for the next --incremental run.
+fn save_marker(args: &Args, out_dir: &Path, newest_liked: Option<NumericId>) -> Result<()> {
+    match newest_liked {
+        Some(newest_liked) if args.incremental && out_dir.exists() => {
+            marker::save(out_dir, newest_liked)?;
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// State shared by concurrent downloads.
+struct Downloader<'a> {
+    args: &'a Args,
+    permalinks: Permalinks,
+    names: NameBuilder,
+    out_dir: &'a Path,
+    /// Refreshes expired media urls. Unset when downloading with --download-from, which
+    /// makes no API requests.
+    bot: Option<&'a Bot>,
+    client: download::Client,
+    sink: Sink,
+    index: std::sync::Mutex<Index>,
+    manifest_entries: std::sync::Mutex<Vec<manifest::Entry>>,
+    /// Prepended to manifest filenames with --manifest-relative.
+    manifest_prefix: Option<String>,
+    /// Writes manifest entries as they are recorded, with --stream-manifest.
+    manifest_writer: Option<std::sync::Mutex<manifest::StreamWriter>>,
+    /// Filenames written to in this run, with the tweet and media key of the image
+    /// written, so images that would land on the same path are caught even before
+    /// either has been written.
+    claimed: std::sync::Mutex<HashMap<String, (NumericId, String)>>,
+    hook: Option<Hook>,
+    adaptive_limit: Option<AdaptiveLimit>,
+    progress: indicatif::ProgressBar,
+    downloaded_count: AtomicUsize,
+    downloaded_bytes: AtomicU64,
+    not_modified_count: AtomicUsize,
+    duplicate_count: AtomicUsize,
+    collision_count: AtomicUsize,
+    hook_failure_count: AtomicUsize,
+    failed_count: AtomicUsize,
+    events: std::sync::Mutex<Vec<RunEvent>>,
+    started: std::time::Instant,
+}
+
+impl<'a> Downloader<'a> {
+    fn event(&self, event: RunEvent) {
+        self.events
+            .lock()
+            .expect("events lock poisoned")
+            .push(event);
+    }
+
+    async fn download(&self, image_ref: ImageRef) -> Result<()> {
+        let url = image_ref.url.to_string();
+        let result = match self.adaptive_limit.as_ref() {
+            Some(adaptive_limit) => {
+                let permit = adaptive_limit.acquire().await;
+                let result = self.write(image_ref).await;
+                adaptive_limit.release(permit, result.is_ok());
+                result
+            }
+            None => self.write(image_ref).await,
+        };
+        if let Err(error) = &result {
+            self.failed_count.fetch_add(1, Ordering::SeqCst);
+            let status = error
+                .chain()
+                .find_map(|source| source.downcast_ref::<download::Error>())
+                .and_then(download::Error::status);
+            self.event(RunEvent::Failed {
+                url,
+                status: status.map(|status| status.as_u16()),
+                error: format!("{error:#}"),
+            });
+        }
+        result
+    }
+
+    /// Log a line summarising download progress every `interval`. Never finishes, so
+    /// should be dropped once downloads are done.
+    async fn log_status(&self, interval: Duration) {
+        let total = self.progress.length().unwrap_or_default();
+        loop {
+            tokio::time::sleep(interval).await;
+            let done = self.progress.position();
+            let failed =
+                u64::try_from(self.failed_count.load(Ordering::SeqCst)).expect("usize in u64");
+            let elapsed = self.started.elapsed();
+            let finished = done + failed;
+            let remaining = if finished > 0 {
+                let left = total.saturating_sub(finished);
+                format!(
+                    ", about {} remaining",
+                    indicatif::HumanDuration(elapsed.mul_f64(left as f64 / finished as f64))
+                )
+            } else {
+                String::new()
+            };
+            log::info!(
+                "Downloaded {}/{} images, {} failed, {} in {}{}",
+                done,
+                total,
+                failed,
+                indicatif::HumanBytes(self.downloaded_bytes.load(Ordering::SeqCst)),
+                indicatif::HumanDuration(elapsed),
+                remaining
+            );
+        }
+    }
+
+    /// Add a downloaded file to the manifest, writing it out straight away if streaming.
+    fn record_manifest(&self, mut entry: manifest::Entry) -> Result<()> {
+        if let Some(prefix) = &self.manifest_prefix {
+            entry.filename.insert_str(0, prefix);
+        }
+        match &self.manifest_writer {
+            Some(manifest_writer) => manifest_writer
+                .lock()
+                .expect("manifest lock poisoned")
+                .append(&entry)?,
+            None => self
+                .manifest_entries
+                .lock()
+                .expect("manifest lock poisoned")
+                .push(entry),
+        }
+        Ok(())
+    }
+
+    /// Count a completed download of `size` bytes, and show the total written so far
+    /// alongside the progress bar.
+    fn record_download(&self, size: u64) {
+        self.downloaded_count.fetch_add(1, Ordering::SeqCst);
+        let total = self.downloaded_bytes.fetch_add(size, Ordering::SeqCst) + size;
+        self.progress
+            .set_message(format!("Downloaded {}", indicatif::HumanBytes(total)));
+    }
+
+    /// Download an image to wherever output is written.
+    async fn write(&self, image_ref: ImageRef) -> Result<()> {
+        match &self.sink {
+            Sink::Local => self.download_image(image_ref).await,
+            Sink::Bucket(bucket) => self.upload_image(bucket, image_ref).await,
+        }
+    }
+
+    /// Download an image into a bucket. Local only features, such as collision handling
+    /// and captions, don't apply.
+    async fn upload_image(&self, bucket: &Bucket, image_ref: ImageRef) -> Result<()> {
+        let filename = self.names.filename(&image_ref);
+        let checksum = download::media(&self.client, image_ref.url.clone(), bucket, &filename)
+            .await
+            .with_context(|| {
+                format!(
+                    "Failed uploading '{}' to '{}'",
+                    image_ref.url,
+                    bucket.key(&filename)
+                )
+            })?;
+        self.record_download(checksum.size);
+        self.index
+            .lock()
+            .expect("index lock poisoned")
+            .insert_author(image_ref.tweet.username.clone());
+        self.record_manifest(manifest::Entry::new(
+            &image_ref,
+            filename,
+            self.args
+                .permalink_in_manifest
+                .then(|| self.permalinks.link(&image_ref.tweet)),
+            checksum,
+        ))?;
+        self.progress.inc(1);
+        Ok(())
+    }
+
+    /// Download a photo at a smaller size than requested, after the requested size
+    /// wasn't found. Returns the original error if no smaller size is found either.
+    async fn fetch_fallback_size(
+        &self,
+        image_ref: &mut ImageRef,
+        filename: &str,
+        error: download::Error,
+    ) -> std::result::Result<download::Fetched, download::Error> {
+        let fetched = download::fallback_size(
+            &self.client,
+            &image_ref.url,
+            self.args.image_size.fallbacks(),
+            self.out_dir,
+            filename,
+            error,
+            |url| {
+                log::debug!("'{}' not found, trying '{}'", image_ref.url, url);
+                self.event(RunEvent::Retry {
+                    url: url.to_string(),
+                    reason: format!("'{}' not found", image_ref.url),
+                });
+            },
         )
-    })?;
-    let client = reqwest::Client::new();
-    let progress = Arc::new(indicatif::ProgressBar::new(
-        image_refs.len().try_into().expect("usize in u64"),
-    ));
-    {
-        let results: Vec<Result<()>> = stream::iter(image_refs)
-            .map(|image_ref| {
-                let progress = progress.clone();
-                let client = &client;
-                async move {
-                    let mut path = args.out_dir.clone();
-                    path.push(image_ref.filename());
-                    download::file(&client, image_ref.url.clone(), &path)
+        .await?;
+        let (url, fetched) = fetched;
+        log::debug!("Fetched '{}' for '{}'", url, image_ref.media_key);
+        image_ref.url = url;
+        Ok(fetched)
+    }
+
+    async fn download_image(&self, mut image_ref: ImageRef) -> Result<()> {
+        let args = self.args;
+        let reencode = args.reencode.filter(|_| image_ref.kind == MediaType::Photo);
+        let mut filename = self.names.filename(&image_ref);
+        if let Some(reencode) = reencode {
+            filename = reencode::with_extension(&filename, reencode.extension());
+        }
+        // Where this media was downloaded to by an earlier run, which it may overwrite.
+        let downloaded_to = self
+            .index
+            .lock()
+            .expect("index lock poisoned")
+            .get(&image_ref.media_key)
+            .map(|entry| entry.filename.clone());
+        // Claims are checked and made under one lock, so concurrent downloads can't pick
+        // the same name.
+        let (collided, resolved) = {
+            let mut claimed = self.claimed.lock().expect("claimed lock poisoned");
+            let claimed_by = |name: &str| {
+                claimed
+                    .get(name)
+                    .filter(|(_, media_key)| *media_key != image_ref.media_key)
+                    .map(|(tweet_id, _)| *tweet_id)
+            };
+            let taken = |name: &str| {
+                claimed_by(name).is_some()
+                    || (self.out_dir.join(name).exists() && downloaded_to.as_deref() != Some(name))
+            };
+            let collided = taken(&filename);
+            let claimed_by_tweet = claimed_by(&filename);
+            if let Some(tweet_id) = claimed_by_tweet {
+                log::warn!(
+                    "Tweets {} and {} both have media named '{filename}'",
+                    tweet_id,
+                    image_ref.tweet.id
+                );
+            }
+            // A file claimed in this run may still be downloading, so it is never overwritten.
+            let on_collision = match args.on_collision {
+                OnCollision::Overwrite if claimed_by_tweet.is_some() => OnCollision::Rename,
+                on_collision => on_collision,
+            };
+            let resolved = download::resolve_collision(&filename, on_collision, taken);
+            if let Some(resolved) = &resolved {
+                claimed.insert(
+                    resolved.clone(),
+                    (image_ref.tweet.id, image_ref.media_key.clone()),
+                );
+            }
+            (collided, resolved)
+        };
+        if collided {
+            self.collision_count.fetch_add(1, Ordering::SeqCst);
+            match resolved {
+                None => {
+                    log::warn!("Skipping '{filename}', a different file already exists");
+                    self.event(RunEvent::Skipped {
+                        id: image_ref.media_key.clone(),
+                        reason: format!("'{filename}' already exists"),
+                    });
+                    self.progress.inc(1);
+                    return Ok(());
+                }
+                Some(resolved) if resolved == filename => {
+                    log::warn!("Overwriting '{filename}', a different file already exists");
+                }
+                Some(resolved) => {
+                    log::warn!("Renaming '{filename}' to '{resolved}', as it already exists");
+                    filename = resolved;
+                }
+            }
+        }
+        let mut path = self.out_dir.join(&filename);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create directory '{}'", parent.display()))?;
+        }
+        let previous = if args.conditional && path.exists() {
+            self.index
+                .lock()
+                .expect("index lock poisoned")
+                .get(&image_ref.media_key)
+                .filter(|entry| entry.filename == filename)
+                .map(|entry| entry.validators.clone())
+        } else {
+            None
+        };
+        let fetched = match download::conditional_media(
+            &self.client,
+            image_ref.url.clone(),
+            self.out_dir,
+            &filename,
+            previous.as_ref(),
+        )
+        .await
+        {
+            Err(error)
+                if args.refresh_expired
+                    && self.bot.is_some()
+                    && matches!(
+                        error.status(),
+                        Some(StatusCode::FORBIDDEN | StatusCode::NOT_FOUND)
+                    ) =>
+            {
+                log::debug!("Refreshing expired url '{}'", image_ref.url);
+                self.event(RunEvent::Retry {
+                    url: image_ref.url.to_string(),
+                    reason: format!("url expired ({})", error),
+                });
+                let url = self
+                    .bot
+                    .expect("checked above")
+                    .refresh_media_url(image_ref.tweet.id, &image_ref.media_key)
+                    .await
+                    .context("Failed to refresh media url")?;
+                match url {
+                    Some(url) => {
+                        image_ref.url = url;
+                        args.image_size.apply(&mut image_ref.url);
+                        if args.strip_query {
+                            download::strip_query(&mut image_ref.url);
+                        }
+                        download::conditional_media(
+                            &self.client,
+                            image_ref.url.clone(),
+                            self.out_dir,
+                            &filename,
+                            None,
+                        )
                         .await
-                        .with_context(|| {
-                            format!("Failed writing '{}' to '{}'", image_ref.url, path.display())
-                        })?;
-                    progress.inc(1);
-                    Ok::<(), anyhow::Error>(())
+                    }
+                    None => Err(error),
                 }
-            })
-            .buffer_unordered(args.download_n)
-            .collect()
-            .await;
-        results.into_iter().collect::<Result<_>>()?;
+            }
+            result => result,
+        };
+        let fetched = match fetched {
+            Err(error)
+                if image_ref.kind == MediaType::Photo
+                    && error.status() == Some(StatusCode::NOT_FOUND) =>
+            {
+                self.fetch_fallback_size(&mut image_ref, &filename, error)
+                    .await
+            }
+            result => result,
+        }
+        .with_context(|| format!("Failed writing '{}' to '{}'", image_ref.url, path.display()))?;
+
+        let mut reencoded = false;
+        if let (Some(reencode), download::Fetched::Downloaded { .. }) = (reencode, &fetched) {
+            match reencode.apply(&path) {
+                Ok(()) => reencoded = true,
+                Err(error) => {
+                    let extension = image_ref
+                        .internal_filename
+                        .rsplit_once('.')
+                        .map_or("jpg", |(_, extension)| extension);
+                    let original = reencode::with_extension(&filename, extension);
+                    log::warn!(
+                        "Keeping '{original}' as downloaded: {:#}",
+                        anyhow::Error::new(error)
+                    );
+                    let original_path = self.out_dir.join(&original);
+                    std::fs::rename(&path, &original_path)
+                        .with_context(|| format!("Failed to rename '{}'", path.display()))?;
+                    filename = original;
+                    path = original_path;
+                }
+            }
+        }
+
+        let mut embedded = false;
+        if let (true, download::Fetched::Downloaded { .. }) = (args.embed_png_metadata, &fetched) {
+            let permalink = self.permalinks.link(&image_ref.tweet);
+            let metadata = png::Metadata {
+                author: &image_ref.tweet.username,
+                url: &permalink,
+                created_at: image_ref.tweet.created_at,
+                alt_text: image_ref.alt_text.as_deref(),
+            };
+            match png::embed(&path, &metadata) {
+                Ok(is_png) => embedded = is_png,
+                Err(error) => log::warn!(
+                    "Failed to embed metadata in '{}': {:#}",
+                    path.display(),
+                    anyhow::Error::new(error)
+                ),
+            }
+        }
+
+        let mut written = false;
+        let checksum = match fetched {
+            download::Fetched::Downloaded {
+                validators,
+                checksum,
+            } => {
+                self.record_download(checksum.size);
+                let mut index = self.index.lock().expect("index lock poisoned");
+                let canonical = index
+                    .canonical_filename(&checksum.sha256)
+                    .filter(|canonical| {
+                        *canonical != filename && self.out_dir.join(canonical).exists()
+                    })
+                    .map(ToOwned::to_owned);
+                match (args.dedupe_across_runs, canonical) {
+                    (Some(dedupe), Some(canonical)) => {
+                        log::debug!("'{filename}' duplicates '{canonical}'");
+                        dedupe
+                            .apply(&self.out_dir.join(&canonical), &path)
+                            .with_context(|| {
+                                format!("Failed to deduplicate '{}'", path.display())
+                            })?;
+                        self.duplicate_count.fetch_add(1, Ordering::SeqCst);
+                        if dedupe == Dedupe::Skip {
+                            filename = canonical;
+                        } else {
+                            written = true;
+                        }
+                    }
+                    _ => {
+                        index.insert_hash(checksum.sha256.clone(), filename.clone());
+                        written = true;
+                    }
+                }
+                index.insert(
+                    image_ref.media_key.clone(),
+                    index::Entry {
+                        filename: filename.clone(),
+                        validators,
+                        tweet: Some(index::Tweet {
+                            username: image_ref.tweet.username.clone(),
+                            permalink: self.permalinks.link(&image_ref.tweet),
+                            created_at: image_ref.tweet.created_at,
+                        }),
+                    },
+                );
+                checksum
+            }
+            download::Fetched::NotModified => {
+                self.not_modified_count.fetch_add(1, Ordering::SeqCst);
+                Checksum::of_file(&path)
+                    .with_context(|| format!("Failed to checksum '{}'", path.display()))?
+            }
+        };
+        self.index
+            .lock()
+            .expect("index lock poisoned")
+            .insert_author(image_ref.tweet.username.clone());
+        if args.caption_sidecar {
+            let mut sidecar_path = path.clone().into_os_string();
+            sidecar_path.push(".txt");
+            let sidecar_path = PathBuf::from(sidecar_path);
+            let tco = if args.caption_strip_tco {
+                TcoLinks::Strip
+            } else if args.resolve_tco {
+                TcoLinks::Resolve
+            } else {
+                TcoLinks::Keep
+            };
+            std::fs::write(
+                &sidecar_path,
+                caption::sidecar(&image_ref.tweet, &self.permalinks, tco),
+            )
+            .with_context(|| format!("Failed writing caption '{}'", sidecar_path.display()))?;
+        }
+        if let Some(hook) = self.hook.as_ref().filter(|_| written) {
+            match hook.run(&path, &image_ref).await {
+                Ok(true) => {}
+                Ok(false) => {
+                    self.hook_failure_count.fetch_add(1, Ordering::SeqCst);
+                }
+                Err(error) => {
+                    log::warn!("Failed to run hook for '{}': {}", path.display(), error);
+                    self.hook_failure_count.fetch_add(1, Ordering::SeqCst);
+                }
+            }
+        }
+        // The index records the checksum of the original download, for dedupe, but the
+        // manifest must match the re-encoded or embedded file on disk.
+        let checksum = if reencoded || embedded {
+            let path = self.out_dir.join(&filename);
+            Checksum::of_file(&path)
+                .with_context(|| format!("Failed to checksum '{}'", path.display()))?
+        } else {
+            checksum
+        };
+        self.record_manifest(manifest::Entry::new(
+            &image_ref,
+            filename,
+            args.permalink_in_manifest
+                .then(|| self.permalinks.link(&image_ref.tweet)),
+            checksum,
+        ))?;
+        self.progress.inc(1);
+        Ok(())
+    }
+
+    /// Persist the index and manifest, and record totals in the summary.
+    fn finish(self, summary: &mut ArchiveSummary) -> Result<()> {
+        self.progress.finish_and_clear();
+        let index = self.index.into_inner().expect("index lock poisoned");
+        index.save(self.out_dir)?;
+        if let Some(manifest_writer) = self.manifest_writer {
+            manifest_writer
+                .into_inner()
+                .expect("manifest lock poisoned")
+                .finish()?;
+        } else if let Some(manifest_path) = &self.args.manifest {
+            let manifest_entries = self
+                .manifest_entries
+                .into_inner()
+                .expect("manifest lock poisoned");
+            manifest::save(manifest_path, &manifest_entries)?;
+        }
+        if self.args.html_index {
+            let gallery_items = index.gallery_items(self.out_dir);
+            let path = self.out_dir.join(gallery::FILENAME);
+            std::fs::write(&path, gallery::render(&gallery_items))
+                .with_context(|| format!("Failed writing gallery '{}'", path.display()))?;
+        }
+        summary.downloaded = self.downloaded_count.into_inner();
+        summary.bytes = self.downloaded_bytes.into_inner();
+        summary.not_modified = self.not_modified_count.into_inner();
+        summary.duplicates = self.duplicate_count.into_inner();
+        summary.collisions = self.collision_count.into_inner();
+        summary.hook_failures = self.hook_failure_count.into_inner();
+        summary
+            .events
+            .extend(self.events.into_inner().expect("events lock poisoned"));
+        Ok(())
+    }
+}
+
+/// Check a login works with one API request, and that it grants every scope needed.
+async fn probe(args: &Args) -> Result<()> {
+    let access_token = login(args).await?;
+    let scopes: Vec<String> = access_token
+        .scopes()
+        .iter()
+        .map(ToString::to_string)
+        .collect();
+    let missing: Vec<String> = auth::missing_scopes(access_token.scopes())
+        .iter()
+        .map(ToString::to_string)
+        .collect();
+    let bot = bot(args, access_token)?;
+    let user = bot
+        .logged_in_user()
+        .await
+        .context("Failed to look up the logged in user")?;
+    println!("Logged in as @{}", user.username);
+    println!("Granted scopes: {}", scopes.join(" "));
+    if !missing.is_empty() {
+        anyhow::bail!("Login is missing the {} scopes", missing.join(", "));
+    }
+    Ok(())
+}
+
+/// Fetch pages of liked tweets, without downloading anything, to estimate the API
+/// requests an archive run would make.
+async fn estimate(args: &Args) -> Result<()> {
+    let out_dir = args.out_dir.as_deref().expect("required by clap");
+    let since = if args.incremental {
+        marker::load(out_dir)?
+    } else {
+        None
+    };
+    let access_token = login(args).await?;
+    let bot = bot(args, access_token)?;
+    let (pages, _) =
+        fetch_liked_pages(args, &bot, since, created_after(args, &SystemClock)).await?;
+    let cached_authors = bot.usernames().await.into_keys().collect();
+    let estimate = Estimate::of_pages(
+        &pages,
+        &cached_authors,
+        args.no_username_cache,
+        args.include_quoted_media,
+        args.include_videos,
+    );
+    estimate.log();
+    log::info!(
+        "This estimate used {} requests itself, which count against the same limits",
+        estimate.pages + 1
+    );
+    Ok(())
+}
+
+async fn verify(args: &Args) -> Result<()> {
+    let out_dir = args.out_dir.as_deref().expect("required by clap");
+    let manifest_path = args.manifest.as_deref().expect("required by clap");
+    let entries = manifest::load(manifest_path)?;
+    // Filenames in a relative manifest are found from the manifest, wherever it is now.
+    let files_dir = if args.manifest_relative {
+        manifest::manifest_dir(manifest_path)
+    } else {
+        out_dir
+    };
+    let _lock = if args.repair {
+        Some(Lock::acquire(out_dir, args.force)?)
+    } else {
+        None
+    };
+    let client = download_client(args);
+
+    log::info!("Verifying {} files", entries.len());
+    let progress = progress::bar(entries.len().try_into().expect("usize in u64"), "Verified");
+    let (mut ok, mut repaired, mut missing, mut corrupted) = (0, 0, 0, 0);
+    for entry in entries.iter() {
+        let path = files_dir.join(&entry.filename);
+        let check = || {
+            manifest::verify_entry(files_dir, entry)
+                .with_context(|| format!("Failed to verify '{}'", path.display()))
+        };
+        let mut verified = check()?;
+        if verified != Verified::Ok && args.repair {
+            let url = entry.url.parse().context("Invalid url in manifest")?;
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent).with_context(|| {
+                    format!("Failed to create directory '{}'", parent.display())
+                })?;
+            }
+            download::media(&client, url, files_dir, &entry.filename)
+                .await
+                .with_context(|| {
+                    format!("Failed writing '{}' to '{}'", entry.url, path.display())
+                })?;
+            if check()? == Verified::Ok {
+                verified = Verified::Ok;
+                repaired += 1;
+                log::info!("Repaired '{}'", entry.filename);
+            }
+        }
+        match verified {
+            Verified::Ok => ok += 1,
+            Verified::Missing => {
+                missing += 1;
+                progress.suspend(|| log::warn!("Missing '{}'", entry.filename));
+            }
+            Verified::Corrupted => {
+                corrupted += 1;
+                progress.suspend(|| log::warn!("Corrupted '{}'", entry.filename));
+            }
+        }
+        progress.inc(1);
+    }
+    progress.finish_and_clear();
+
+    log::info!(
+        "Verified {} files: {} ok ({} repaired), {} missing, {} corrupted",
+        entries.len(),
+        ok,
+        repaired,
+        missing,
+        corrupted
+    );
+    if missing + corrupted > 0 {
+        anyhow::bail!("Verification failed for {} files", missing + corrupted);
     }
     Ok(())
 }
 
 #[tokio::main]
 async fn main() {
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"))
+        .format(|buf, record| {
+            let timestamp = buf.timestamp();
+            let level = buf.default_styled_level(record.level());
+            write!(
+                buf,
+                "[{} {:<5} {}] {}{}",
+                timestamp,
+                level,
+                record.module_path().unwrap_or_default(),
+                record.args(),
+                controls::line_ending()
+            )
+        })
+        .init();
     log::debug!("Initialised logging");
     let args = Args::parse();
-
-    if let Err(error) = run(&args).await {
+    let started = std::time::Instant::now();
+    let mut summary = ArchiveSummary::default();
+    let result = if args.logout {
+        logout(&args).await
+    } else if args.auth_start {
+        start_login(&args)
+    } else if args.verify {
+        verify(&args).await
+    } else if args.probe {
+        probe(&args).await
+    } else if args.estimate {
+        estimate(&args).await
+    } else if args.stdout {
+        write_stdout(&args).await
+    } else if let Some(path) = &args.fetch_to {
+        fetch_to(&args, path, &mut summary).await
+    } else if let Some(accounts) = &args.accounts {
+        run_accounts(&args, accounts, &mut summary).await
+    } else {
+        run(&args, &mut summary).await
+    };
+    summary.error = result.as_ref().err().map(|error| format!("{error:#}"));
+    summary.duration_secs = started.elapsed().as_secs_f64();
+    if let Some(metrics_file) = &args.metrics_file {
+        if let Err(error) = metrics::write(metrics_file, &summary) {
+            log::error!(
+                "Failed to write metrics '{}': {}",
+                metrics_file.display(),
+                error
+            );
+        }
+    }
+    if let Some(events_json) = &args.events_json {
+        let contents = serde_json::to_vec(&summary.events).expect("events are serializable");
+        if let Err(error) = std::fs::write(events_json, contents) {
+            log::error!(
+                "Failed to write events '{}': {}",
+                events_json.display(),
+                error
+            );
+        }
+    }
+    if args.report_json {
+        println!(
+            "{}",
+            serde_json::to_string(&summary).expect("summary is serializable")
+        );
+    }
+    if let Err(error) = result {
         log::error!("Runtime error:");
         for error in error.chain() {
             log::error!("--> {}", error);
         }
+        std::process::exit(1);
     }
 }
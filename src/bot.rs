@@ -1,21 +1,78 @@
-use std::collections::HashMap;
+use crate::api::{RateLimitConfig, RateLimitedApi, TokenRefresh};
+use crate::clock::{Clock, SystemClock};
+use crate::summary::RunEvent;
+use crate::video::{self, VideoQuality};
+use futures::{StreamExt, TryStreamExt};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Arc;
 use thiserror::Error;
-use time::format_description::well_known::Iso8601;
-use tokio::sync::RwLock;
-use twitter_v2::api_result::{ApiResponse, PaginableApiResponse};
+use tokio::sync::{OnceCell, RwLock};
+use twitter_v2::api_result::{ApiPayload, ApiResponse};
 use twitter_v2::authorization::Oauth2Token;
-use twitter_v2::data::{MediaType, Tweet};
-use twitter_v2::id::NumericId;
+use twitter_v2::data::{Media, MediaType, ReferencedTweetKind, Tweet, User};
+use twitter_v2::id::{NumericId, StringId};
 use twitter_v2::meta::{PaginationMeta, ResultCountMeta};
 use twitter_v2::query::{MediaField, TweetExpansion, TweetField, UserField};
-use twitter_v2::TwitterApi;
 
 #[derive(Error, Debug)]
 pub enum Error {
-    #[error("Twitter API violated an expected invariant: {}", 0)]
+    #[error("Twitter API violated an expected invariant: {0}")]
     TwitterApiInvariant(&'static str),
+    /// The API rate limit was exceeded. `reset` is when the limit resets, if known.
+    #[error("Twitter API rate limit exceeded")]
+    RateLimited { reset: Option<time::OffsetDateTime> },
+    #[error("Twitter login has expired or been revoked")]
+    AuthExpired,
+    #[error(
+        "Twitter login has expired and has no refresh token, as offline.access wasn't granted. \
+         Log in again with --offline-access"
+    )]
+    NoRefreshToken,
+    #[error("Failed to refresh Twitter login")]
+    RefreshFailed(#[source] twitter_v2::Error),
+    #[error("No Twitter user @{username}")]
+    UserNotFound { username: String },
+    #[error("Not permitted to read the likes of @{username}, as they are protected or access was denied")]
+    LikesForbidden { username: String },
+    #[error("Not permitted to search tweets, which needs Basic or higher API access")]
+    SearchForbidden,
+    #[error("Failed to look up user {id}")]
+    UserLookupFailed {
+        id: NumericId,
+        source: twitter_v2::Error,
+    },
     #[error("Twitter client error")]
     TwitterClient(#[from] twitter_v2::Error),
+    #[error("Invalid bot configuration: {0}")]
+    Config(&'static str),
+}
+
+impl Error {
+    /// Classify an error from the Twitter client, so callers can react to rate limits
+    /// and expired logins without inspecting the response.
+    pub(crate) fn from_client(error: twitter_v2::Error) -> Self {
+        match &error {
+            twitter_v2::Error::Api(api) if api.status == StatusCode::TOO_MANY_REQUESTS => {
+                Self::RateLimited { reset: None }
+            }
+            twitter_v2::Error::Api(api) if api.status == StatusCode::UNAUTHORIZED => {
+                Self::AuthExpired
+            }
+            _ => Self::TwitterClient(error),
+        }
+    }
+
+    /// Whether the error is likely to pass if the request is retried, such as a dropped
+    /// connection or a server error.
+    pub(crate) fn is_transient(&self) -> bool {
+        match self {
+            Self::TwitterClient(twitter_v2::Error::Request(_)) => true,
+            Self::TwitterClient(twitter_v2::Error::Api(api)) => api.status.is_server_error(),
+            _ => false,
+        }
+    }
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -32,211 +89,1558 @@ impl<T> TwitterInvariantExt<T> for Option<T> {
     }
 }
 
-pub type UsernameCache = RwLock<HashMap<NumericId, String>>;
+/// Most tweets that can be looked up in one request.
+const MAX_TWEET_LOOKUP: usize = 100;
+
+/// Most users that can be looked up in one request.
+pub const MAX_USER_LOOKUP: usize = 100;
+
+const MEDIA_FIELDS: [MediaField; 4] = [
+    MediaField::Type,
+    MediaField::Url,
+    MediaField::Variants,
+    MediaField::AltText,
+];
+
+/// Tweet fields needed to extract and name media.
+const TWEET_FIELDS: [TweetField; 8] = [
+    TweetField::Id,
+    TweetField::Attachments,
+    TweetField::Text,
+    TweetField::AuthorId,
+    TweetField::Entities,
+    TweetField::CreatedAt,
+    TweetField::ReferencedTweets,
+    TweetField::PossiblySensitive,
+];
+
+/// Details of a tweet author, looked up once per run.
+#[derive(Debug, Clone)]
+pub struct Author {
+    pub username: String,
+    /// Only requested when author avatars are being downloaded.
+    pub profile_image_url: Option<url::Url>,
+    /// Only requested when filtering by follower count.
+    pub followers: Option<usize>,
+}
+
+impl From<User> for Author {
+    fn from(user: User) -> Self {
+        Self {
+            username: user.username,
+            profile_image_url: user.profile_image_url,
+            followers: user
+                .public_metrics
+                .map(|public_metrics| public_metrics.followers_count),
+        }
+    }
+}
+
+pub type AuthorCache = RwLock<HashMap<NumericId, Author>>;
 
 pub struct Bot {
-    api: TwitterApi<Oauth2Token>,
-    username_cache: UsernameCache,
+    api: RateLimitedApi,
+    author_cache: AuthorCache,
+    /// Lookups of uncached authors, shared so concurrent tweets by the same author make
+    /// one request between them.
+    author_lookups: std::sync::Mutex<HashMap<NumericId, Arc<OnceCell<Author>>>>,
 }
 
-#[derive(Debug, Clone)]
+/// Configures a [`Bot`], for when the defaults of [`Bot::new`] don't fit.
+#[derive(Debug)]
+pub struct BotBuilder {
+    access_token: Oauth2Token,
+    rate_limit: RateLimitConfig,
+    refresh: Option<TokenRefresh>,
+    clock: Arc<dyn Clock>,
+}
+
+impl BotBuilder {
+    /// How requests react to rate limits and transient failures.
+    pub fn rate_limit(mut self, rate_limit: RateLimitConfig) -> Self {
+        self.rate_limit = rate_limit;
+        self
+    }
+
+    /// Refresh the login if it expires mid-run, rather than failing.
+    pub fn refresh(mut self, refresh: TokenRefresh) -> Self {
+        self.refresh = Some(refresh);
+        self
+    }
+
+    /// Read the current time from this clock, rather than the system clock.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn build(self) -> Result<Bot> {
+        Ok(Bot {
+            api: RateLimitedApi::new(self.access_token, self.rate_limit, self.refresh, self.clock)?,
+            author_cache: Default::default(),
+            author_lookups: Default::default(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TweetRef {
+    #[serde(with = "time::serde::rfc3339")]
     pub created_at: time::OffsetDateTime,
     pub username: String,
     pub id: NumericId,
+    pub text: String,
+    /// Expanded urls for the `t.co` short links in the text, keyed by short link.
+    pub expanded_urls: HashMap<String, String>,
+    /// Number of media attached to the tweet that are being archived.
+    pub media_count: usize,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ImageRef {
     pub tweet: TweetRef,
+    pub media_key: String,
+    /// Position of this media among the tweet's attachments.
+    pub media_index: usize,
+    pub kind: MediaType,
     pub internal_filename: String,
     pub url: url::Url,
+    /// Description of the media added by the author, if any.
+    pub alt_text: Option<String>,
+}
+
+/// Image references found on a page, along with counts of media that were skipped.
+#[derive(Debug, Clone, Default)]
+pub struct ProcessOutcome {
+    pub image_refs: Vec<ImageRef>,
+    /// Photos the API returned without a url, such as withheld media.
+    pub skipped_no_url: usize,
+    /// Profile images of authors, when requested.
+    pub avatars: BTreeMap<NumericId, AvatarRef>,
+    /// Liked tweets with no photos to download, such as text-only tweets or polls.
+    pub no_media: Vec<NumericId>,
+    /// Tweets with photos the API returned without a creation date, such as withheld
+    /// tweets. Handled by `ProcessOptions::on_missing_date`.
+    pub missing_date: usize,
+    /// Media the API returned with a url that isn't a web url with a filename.
+    pub invalid_url: usize,
+    /// Media skipped by `ProcessOptions::exclude_extensions`.
+    pub excluded_by_extension: usize,
+    /// Media found in tweets the API marked as possibly sensitive. These are still
+    /// downloaded, as the API doesn't withhold them.
+    pub sensitive: usize,
+    /// Media referenced by tweets but missing from the response's includes, as when the
+    /// response was truncated.
+    pub missing_media: usize,
+    /// Tweets and media skipped, with the reason why.
+    pub events: Vec<RunEvent>,
+}
+
+impl ProcessOutcome {
+    /// Combine the results of another page into this one.
+    pub fn merge(&mut self, other: ProcessOutcome) {
+        self.image_refs.extend(other.image_refs);
+        self.skipped_no_url += other.skipped_no_url;
+        self.avatars.extend(other.avatars);
+        self.no_media.extend(other.no_media);
+        self.missing_date += other.missing_date;
+        self.invalid_url += other.invalid_url;
+        self.excluded_by_extension += other.excluded_by_extension;
+        self.sensitive += other.sensitive;
+        self.missing_media += other.missing_media;
+        self.events.extend(other.events);
+    }
+}
+
+/// A reference to the profile image of a tweet author.
+#[derive(Debug, Clone)]
+pub struct AvatarRef {
+    pub username: String,
+    pub url: url::Url,
 }
 
-impl ImageRef {
+impl AvatarRef {
+    /// Filename to store the avatar as, relative to the output directory.
     pub fn filename(&self) -> String {
-        let created_at = self
-            .tweet
-            .created_at
-            .format(&Iso8601::DEFAULT)
-            .expect("format created at");
-        format!(
-            "{} {} {} {}",
-            created_at, self.tweet.username, self.tweet.id, self.internal_filename
-        )
+        let extension = self
+            .url
+            .path_segments()
+            .and_then(|mut segments| segments.next_back())
+            .and_then(|filename| filename.rsplit_once('.'))
+            .map(|(_, extension)| extension)
+            .unwrap_or("jpg");
+        format!("authors/{}.{}", self.username, extension)
+    }
+}
+
+/// Profile image urls point to a small thumbnail by default, with a `_normal` suffix.
+/// Removing it gives the full size original.
+fn full_size_avatar(mut url: url::Url) -> url::Url {
+    let path = url.path().to_owned();
+    if let Some((stem, extension)) = path.rsplit_once('.') {
+        if let Some(stem) = stem.strip_suffix("_normal") {
+            url.set_path(&format!("{stem}.{extension}"));
+        }
+    } else if let Some(stem) = path.strip_suffix("_normal") {
+        url.set_path(stem);
+    }
+    url
+}
+
+/// Parse a media filename extension, such as `png` or `.PNG`, into lowercase without a
+/// dot.
+pub fn parse_extension(extension: &str) -> std::result::Result<String, String> {
+    let extension = extension
+        .trim()
+        .trim_start_matches('.')
+        .to_ascii_lowercase();
+    if extension.is_empty() || !extension.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Err(format!(
+            "expected an extension such as 'png', got '{extension}'"
+        ));
+    }
+    Ok(extension)
+}
+
+/// Options controlling how liked tweets are fetched.
+#[derive(Debug, Clone, Default)]
+pub struct FetchOptions {
+    /// Number of tweets to request per page, between 10 and 100. Defaults to the API's
+    /// page size if unset.
+    pub page_size: Option<usize>,
+    /// Stop paginating after the page containing this tweet. Likes are returned newest
+    /// first, so this fetches only tweets liked since.
+    pub stop_at: Option<NumericId>,
+    /// Pagination token of the page to start from, rather than the most recent likes.
+    pub start_token: Option<String>,
+    /// Stop paginating once enough tweets have been fetched from each author.
+    pub author_sample: Option<AuthorSample>,
+    /// Stop paginating after a page of tweets all created before this time.
+    ///
+    /// Likes are ordered by when they were liked rather than when the tweets were
+    /// created, so this assumes a whole page of old tweets means there are no newer ones
+    /// liked before them.
+    pub created_after: Option<time::OffsetDateTime>,
+    /// Username of the account whose likes are fetched, rather than the logged in user.
+    pub likes_of: Option<String>,
+}
+
+/// Counts of liked tweets fetched from each author, to stop paginating once enough have
+/// been fetched from every author.
+///
+/// Likes aren't grouped by author, so this is best effort: an author who hasn't reached
+/// the cap may still be found further back.
+#[derive(Debug, Clone)]
+pub struct AuthorSample {
+    max: usize,
+    target: Option<usize>,
+    counts: Arc<std::sync::Mutex<HashMap<NumericId, usize>>>,
+}
+
+impl AuthorSample {
+    /// Sample `max` tweets from each author. If `target` is given, the sample is complete
+    /// once that many authors have `max` tweets each.
+    pub fn new(max: usize, target: Option<usize>) -> Self {
+        Self {
+            max,
+            target,
+            counts: Default::default(),
+        }
+    }
+
+    /// Count the tweets on a page. Returns whether the sample is complete, either because
+    /// the target number of authors was reached, or because every tweet on the page was
+    /// from an author already at the cap.
+    fn record(&self, page: &Page) -> bool {
+        let mut counts = self.counts.lock().expect("author counts lock poisoned");
+        let mut below_cap = false;
+        let mut authors = 0;
+        for author_id in page
+            .data()
+            .into_iter()
+            .flatten()
+            .filter_map(|tweet| tweet.author_id)
+        {
+            let count = counts.entry(author_id).or_default();
+            below_cap |= *count < self.max;
+            *count += 1;
+            authors += 1;
+        }
+        let complete = counts.values().filter(|count| **count >= self.max).count();
+        let target_reached = self.target.is_some_and(|target| complete >= target);
+        target_reached || (authors > 0 && !below_cap)
+    }
+}
+
+/// Check a pagination token given by the user. Tokens are opaque, so this only rejects
+/// values that can't be tokens at all.
+pub fn parse_pagination_token(token: &str) -> std::result::Result<String, String> {
+    let valid = !token.is_empty()
+        && token
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid {
+        return Err(format!("invalid pagination token '{token}'"));
+    }
+    Ok(token.to_owned())
+}
+
+/// Size of photo Twitter serves, selected with the `name` query parameter.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ImageSize {
+    Small,
+    Medium,
+    Large,
+    /// The original upload.
+    #[default]
+    Orig,
+}
+
+impl ImageSize {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Small => "small",
+            Self::Medium => "medium",
+            Self::Large => "large",
+            Self::Orig => "orig",
+        }
     }
+
+    /// Smaller sizes to try, in order, if this size isn't available. Some older media
+    /// have no original size.
+    pub fn fallbacks(self) -> &'static [ImageSize] {
+        match self {
+            Self::Orig => &[Self::Large, Self::Medium],
+            Self::Small | Self::Medium | Self::Large => &[],
+        }
+    }
+
+    /// Set the size requested by a photo url, replacing any size already requested.
+    pub fn apply(self, url: &mut url::Url) {
+        let pairs: Vec<(String, String)> = url
+            .query_pairs()
+            .filter(|(key, _)| key != "name")
+            .map(|(key, value)| (key.into_owned(), value.into_owned()))
+            .collect();
+        url.query_pairs_mut()
+            .clear()
+            .extend_pairs(pairs)
+            .append_pair("name", self.name());
+    }
+}
+
+/// What to do with a tweet the API returned without a creation date.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum OnMissingDate {
+    /// Don't download its media.
+    Skip,
+    /// Download its media, dated to the unix epoch.
+    #[default]
+    Epoch,
+}
+
+/// Options controlling which media is extracted from a page.
+#[derive(Debug, Clone, Default)]
+pub struct ProcessOptions {
+    /// Also extract media from tweets quoted by liked tweets.
+    pub include_quoted_media: bool,
+    /// Name authors by their numeric id, rather than looking up and caching their
+    /// username.
+    pub author_ids_as_names: bool,
+    /// Also request the profile image url of each author.
+    pub author_avatars: bool,
+    /// Ignore this tweet and any after it in the page, as they were already archived.
+    pub stop_at: Option<NumericId>,
+    /// What to do with tweets returned without a creation date.
+    pub on_missing_date: OnMissingDate,
+    /// Also extract videos and animated GIFs, picking the variant of this quality.
+    pub video_quality: Option<VideoQuality>,
+    /// Fall back to HLS playlists for videos with no suitable MP4 variant.
+    pub allow_hls: bool,
+    /// Size of photos to download.
+    pub image_size: ImageSize,
+    /// Skip liked tweets created before this time.
+    pub created_after: Option<time::OffsetDateTime>,
+    /// Number of tweets in a page to extract media from at once, which mostly bounds
+    /// concurrent author lookups. Zero is treated as one.
+    pub tweet_concurrency: usize,
+    /// Skip media whose filename has one of these extensions, lowercase and without a dot.
+    pub exclude_extensions: Vec<String>,
+    /// Skip media from authors with fewer followers than this, as a rough filter for
+    /// spam and bot accounts.
+    pub min_author_followers: Option<usize>,
 }
 
-pub type Page = ApiResponse<Oauth2Token, Vec<Tweet>, ResultCountMeta>;
+/// A page of tweets with their expansions. Only the payload is kept, so pages of likes
+/// and of search results share a type.
+pub type Page = ApiPayload<Vec<Tweet>, ResultCountMeta>;
+
+/// Where pages of tweets are fetched from.
+#[derive(Debug, Clone)]
+enum Source {
+    /// Tweets liked by the user with this id.
+    Likes(NumericId),
+    /// Recent tweets matching a search query.
+    Search(String),
+}
 
 impl Bot {
+    /// A bot with the default configuration.
     pub fn new(access_token: Oauth2Token) -> Self {
-        let api = TwitterApi::new(access_token);
-        Self {
-            api,
-            username_cache: Default::default(),
+        Self::builder(access_token)
+            .build()
+            .expect("default bot configuration invalid")
+    }
+
+    pub fn builder(access_token: Oauth2Token) -> BotBuilder {
+        BotBuilder {
+            access_token,
+            rate_limit: Default::default(),
+            refresh: None,
+            clock: Arc::new(SystemClock),
         }
     }
 
-    /// Fetch liked tweets with associated metadata like image references.
-    async fn fetch_liked_tweets_first(&self) -> Result<Page> {
-        let user = self
-            .api
-            .get_users_me()
-            .send()
+    /// Usernames of the authors looked up so far, by id.
+    pub async fn usernames(&self) -> BTreeMap<NumericId, String> {
+        self.author_cache
+            .read()
+            .await
+            .iter()
+            .map(|(id, author)| (*id, author.username.clone()))
+            .collect()
+    }
+
+    /// When each pause for the rate limit ended, or will end.
+    pub async fn rate_limit_pauses(&self) -> Vec<time::OffsetDateTime> {
+        self.api.pauses().await
+    }
+
+    /// Look up the logged in user. A failure here would waste the login, so transient
+    /// errors are retried.
+    pub async fn logged_in_user(&self) -> Result<User> {
+        self.api
+            .request_with_retries("look up the logged in user", |api| {
+                let request = api.get_users_me();
+                async move { request.send().await.map(ApiResponse::into_payload) }
+            })
             .await?
             .into_data()
-            .ok_or_invariant("logged in user to exist")?;
+            .ok_or_invariant("logged in user to exist")
+    }
+
+    /// Look up a user by username, such as to fetch their likes.
+    pub async fn user_by_username(&self, username: &str) -> Result<User> {
+        self.api
+            .request_with_retries(&format!("look up @{username}"), |api| {
+                let request = api.get_user_by_username(username);
+                async move { request.send().await.map(ApiResponse::into_payload) }
+            })
+            .await?
+            .into_data()
+            .ok_or_else(|| Error::UserNotFound {
+                username: username.to_owned(),
+            })
+    }
+
+    /// Fetch the first page of search results, or of liked tweets, along with where
+    /// later pages are fetched from.
+    async fn fetch_first(
+        &self,
+        query: Option<String>,
+        options: &FetchOptions,
+    ) -> Result<(Source, Page)> {
+        if let Some(query) = query {
+            let source = Source::Search(query);
+            let first_page = self
+                .fetch_page(&source, options.page_size, options.start_token.as_deref())
+                .await?;
+            return Ok((source, first_page));
+        }
+        let user = match &options.likes_of {
+            Some(username) => self.user_by_username(username).await?,
+            None => self.logged_in_user().await?,
+        };
         let first_page = self
+            .fetch_liked_tweets_page(user.id, options.page_size, options.start_token.as_deref())
+            .await
+            .map_err(|error| match error {
+                Error::TwitterClient(twitter_v2::Error::Api(api))
+                    if api.status == StatusCode::FORBIDDEN && options.likes_of.is_some() =>
+                {
+                    Error::LikesForbidden {
+                        username: user.username.clone(),
+                    }
+                }
+                error => error,
+            })?;
+        Ok((Source::Likes(user.id), first_page))
+    }
+
+    async fn fetch_page(
+        &self,
+        source: &Source,
+        page_size: Option<usize>,
+        pagination_token: Option<&str>,
+    ) -> Result<Page> {
+        match source {
+            Source::Likes(user_id) => {
+                self.fetch_liked_tweets_page(*user_id, page_size, pagination_token)
+                    .await
+            }
+            Source::Search(query) => {
+                self.fetch_search_page(query, page_size, pagination_token)
+                    .await
+            }
+        }
+    }
+
+    /// Fetch a page of tweets liked by a user, with associated metadata like image
+    /// references.
+    ///
+    /// Pages are requested through the bot's client rather than with
+    /// `ApiResponse::next_page`, which would keep using the login the first page was
+    /// fetched with after it is refreshed.
+    async fn fetch_liked_tweets_page(
+        &self,
+        user_id: NumericId,
+        page_size: Option<usize>,
+        pagination_token: Option<&str>,
+    ) -> Result<Page> {
+        self.api
+            .request_with_retries("fetch a page of liked tweets", |api| {
+                let mut request = api.get_user_liked_tweets(user_id);
+                request
+                    .tweet_fields(TWEET_FIELDS)
+                    .expansions([TweetExpansion::AttachmentsMediaKeys])
+                    .media_fields(MEDIA_FIELDS);
+                if let Some(page_size) = page_size {
+                    request.max_results(page_size);
+                }
+                if let Some(pagination_token) = pagination_token {
+                    request.pagination_token(pagination_token);
+                }
+                async move { request.send().await.map(ApiResponse::into_payload) }
+            })
+            .await
+    }
+
+    /// Fetch a page of recent tweets matching a search query, with the same metadata as
+    /// liked tweets. Search needs Basic or higher API access.
+    async fn fetch_search_page(
+        &self,
+        query: &str,
+        page_size: Option<usize>,
+        pagination_token: Option<&str>,
+    ) -> Result<Page> {
+        let payload = self
             .api
-            .get_user_liked_tweets(user.id)
-            .tweet_fields([
-                TweetField::Id,
-                TweetField::Attachments,
-                TweetField::Text,
-                TweetField::AuthorId,
-                TweetField::Entities,
-                TweetField::CreatedAt,
-            ])
-            .expansions([TweetExpansion::AttachmentsMediaKeys])
-            .media_fields([MediaField::Type, MediaField::Url])
-            .send()
-            .await?;
-        Ok(first_page)
+            .request_with_retries("fetch a page of search results", |api| {
+                let mut request = api.get_tweets_search_recent(query);
+                request
+                    .tweet_fields(TWEET_FIELDS)
+                    .expansions([TweetExpansion::AttachmentsMediaKeys])
+                    .media_fields(MEDIA_FIELDS);
+                if let Some(page_size) = page_size {
+                    request.max_results(page_size);
+                }
+                if let Some(pagination_token) = pagination_token {
+                    request.pagination_token(pagination_token);
+                }
+                async move { request.send().await.map(ApiResponse::into_payload) }
+            })
+            .await
+            .map_err(|error| match error {
+                Error::TwitterClient(twitter_v2::Error::Api(api))
+                    if api.status == StatusCode::FORBIDDEN =>
+                {
+                    Error::SearchForbidden
+                }
+                error => error,
+            })?;
+        Ok(ApiPayload {
+            data: payload.data,
+            meta: payload.meta.map(|meta| ResultCountMeta {
+                result_count: meta.result_count,
+                next_token: meta.next_token,
+                previous_token: meta.previous_token,
+            }),
+            includes: payload.includes,
+            errors: payload.errors,
+        })
     }
 
     /// Fetch liked tweets with associated metadata like image references.
-    pub fn fetch_liked_tweets<'a>(&'a self) -> impl futures::Stream<Item = Result<Page>> + 'a {
+    pub fn fetch_liked_tweets<'a>(
+        &'a self,
+        options: FetchOptions,
+    ) -> impl futures::Stream<Item = Result<Page>> + 'a {
+        self.fetch_pages(None, options)
+    }
+
+    /// Fetch recent tweets matching a search query, with the same metadata as liked
+    /// tweets. `FetchOptions::likes_of` is ignored.
+    pub fn fetch_search_tweets<'a>(
+        &'a self,
+        query: &str,
+        options: FetchOptions,
+    ) -> impl futures::Stream<Item = Result<Page>> + 'a {
+        self.fetch_pages(Some(query.to_owned()), options)
+    }
+
+    fn fetch_pages<'a>(
+        &'a self,
+        query: Option<String>,
+        options: FetchOptions,
+    ) -> impl futures::Stream<Item = Result<Page>> + 'a {
         enum State {
-            Unintialised,
+            Unintialised(Option<String>, FetchOptions),
             Errored,
-            Page(Page),
+            Finished,
+            Page { source: Source, page: Page },
         }
 
-        let stream = futures::stream::unfold(State::Unintialised, move |state| async move {
-            let next_page: Result<Option<Page>> = match state {
-                State::Unintialised => self.fetch_liked_tweets_first().await.map(|page| Some(page)),
-                State::Page(current_page) => current_page.next_page().await.map_err(Into::into),
-                State::Errored => return None,
-            };
-            let next_page: Option<Result<Page>> = next_page.transpose();
-            next_page.map(|next_page| {
-                let next_state: State = match next_page.as_ref() {
-                    Ok(next_page) => State::Page(next_page.to_owned()),
-                    Err(_) => State::Errored,
+        let page_size = options.page_size;
+        let stop_at = options.stop_at;
+        let created_after = options.created_after;
+        let author_sample = options.author_sample.clone();
+        let stream = futures::stream::unfold(State::Unintialised(query, options), move |state| {
+            let author_sample = author_sample.clone();
+            async move {
+                let (source, next_page): (Source, Result<Option<Page>>) = match state {
+                    State::Unintialised(query, options) => {
+                        match self.fetch_first(query, &options).await {
+                            Ok((source, page)) => (source, Ok(Some(page))),
+                            Err(error) => return Some((Err(error), State::Errored)),
+                        }
+                    }
+                    State::Page { source, page } => {
+                        let next_page = match page.meta().and_then(|meta| meta.next_token()) {
+                            Some(next_token) => self
+                                .fetch_page(&source, page_size, Some(next_token))
+                                .await
+                                .map(Some),
+                            None => Ok(None),
+                        };
+                        (source, next_page)
+                    }
+                    State::Errored | State::Finished => return None,
                 };
-                (next_page, next_state)
-            })
+                if let Ok(Some(page)) = &next_page {
+                    if let Some(next_token) = page.meta().and_then(|meta| meta.next_token()) {
+                        log::debug!("Next page token: {next_token}");
+                    }
+                }
+                let next_page: Option<Result<Page>> = next_page.transpose();
+                next_page.map(|next_page| {
+                    let next_state: State = match next_page.as_ref() {
+                        Ok(next_page)
+                            if stop_at.is_some_and(|stop_at| {
+                                next_page
+                                    .data()
+                                    .into_iter()
+                                    .flatten()
+                                    .any(|tweet| tweet.id == stop_at)
+                            }) =>
+                        {
+                            State::Finished
+                        }
+                        Ok(next_page)
+                            if created_after.is_some_and(|created_after| {
+                                created_before(next_page, created_after)
+                            }) =>
+                        {
+                            log::info!("Reached tweets older than the maximum age, stopping");
+                            State::Finished
+                        }
+                        Ok(next_page)
+                            if author_sample
+                                .as_ref()
+                                .is_some_and(|author_sample| author_sample.record(next_page)) =>
+                        {
+                            log::info!("Fetched enough tweets from each author, stopping early");
+                            State::Finished
+                        }
+                        Ok(next_page) => State::Page {
+                            source: source.clone(),
+                            page: next_page.to_owned(),
+                        },
+                        Err(_) => State::Errored,
+                    };
+                    (next_page, next_state)
+                })
+            }
         });
         stream
     }
 
-    /// Process tweets with metadata into
-    pub async fn process_page(&self, page: &Page) -> Result<Vec<ImageRef>> {
+    /// Fetch a fresh url for a media item, for when the url from an earlier request
+    /// has expired. Costs one tweet lookup request.
+    pub async fn refresh_media_url(
+        &self,
+        tweet_id: NumericId,
+        media_key: &str,
+    ) -> Result<Option<url::Url>> {
+        let response = self
+            .api
+            .request(|api| {
+                let mut request = api.get_tweet(tweet_id);
+                request
+                    .tweet_fields([TweetField::Attachments])
+                    .expansions([TweetExpansion::AttachmentsMediaKeys])
+                    .media_fields(MEDIA_FIELDS);
+                async move { request.send().await.map(ApiResponse::into_payload) }
+            })
+            .await?;
+        let url = response
+            .includes()
+            .and_then(|includes| includes.media.as_ref())
+            .and_then(|media| {
+                media
+                    .iter()
+                    .find(|media| media.media_key.as_str() == media_key)
+            })
+            .and_then(|media| media.url.clone());
+        Ok(url)
+    }
+
+    /// Process tweets with metadata into image references.
+    pub async fn process_page(
+        &self,
+        page: &Page,
+        options: &ProcessOptions,
+    ) -> Result<ProcessOutcome> {
         let liked_tweets = match page.data() {
             Some(data) => data.to_owned(),
             // If not data, this is the last page and we will stop paginating.
             None => {
                 assert_eq!(page.meta().and_then(|m| m.next_token()), None);
-                return Ok(Vec::new());
+                return Ok(ProcessOutcome::default());
             }
         };
-        let includes = page
-            .includes()
-            .ok_or_invariant("includes in response")?
-            .to_owned();
+        // Pages of text-only tweets have no media to include, but media must be included
+        // if any tweet has some attached.
+        let includes_media: HashMap<_, _> = match page.includes().and_then(|i| i.media.as_ref()) {
+            Some(media) => media
+                .iter()
+                .map(|media| (media.media_key.clone(), media.clone()))
+                .collect(),
+            None if liked_tweets.iter().any(has_media_keys) => {
+                return Err(Error::TwitterApiInvariant("media in includes"))
+            }
+            None => HashMap::new(),
+        };
+        let mut outcome = ProcessOutcome::default();
+        let mut seen_media = HashSet::new();
+        let mut quoted_ids = Vec::new();
+        let mut tweets = Vec::new();
+
+        for tweet in liked_tweets
+            .into_iter()
+            .take_while(|tweet| Some(tweet.id) != options.stop_at)
+        {
+            if let Some((created_at, created_after)) = tweet.created_at.zip(options.created_after) {
+                if created_at < created_after {
+                    continue;
+                }
+            }
+            if options.include_quoted_media {
+                quoted_ids.extend(
+                    tweet
+                        .referenced_tweets
+                        .iter()
+                        .flatten()
+                        .filter(|referenced| referenced.kind == ReferencedTweetKind::Quoted)
+                        .map(|referenced| referenced.id),
+                );
+            }
+            if !has_wanted_media(&tweet, &includes_media, options.video_quality.is_some()) {
+                outcome.no_media.push(tweet.id);
+            }
+            tweets.push(tweet);
+        }
+        outcome.merge(
+            self.extract_all_media(tweets, &includes_media, options, &mut seen_media)
+                .await?,
+        );
+
+        if !quoted_ids.is_empty() {
+            quoted_ids.sort();
+            quoted_ids.dedup();
+            outcome.merge(
+                self.process_quoted(quoted_ids, options, &mut seen_media)
+                    .await?,
+            );
+        }
+
+        Ok(outcome)
+    }
+
+    /// Fetch tweets by id, and extract their media, as when archiving chosen tweets
+    /// rather than likes. Costs one request per 100 tweets.
+    pub async fn process_tweets(
+        &self,
+        ids: &[NumericId],
+        options: &ProcessOptions,
+    ) -> Result<ProcessOutcome> {
+        let mut outcome = ProcessOutcome::default();
+        let mut seen_media = HashSet::new();
+        for ids in ids.chunks(MAX_TWEET_LOOKUP) {
+            outcome.merge(
+                self.process_quoted(ids.to_vec(), options, &mut seen_media)
+                    .await?,
+            );
+        }
+        Ok(outcome)
+    }
 
-        let includes_media: HashMap<_, _> = includes
-            .media
-            .ok_or_invariant("media in includes")?
+    /// Fetch tweets quoted by liked tweets, and extract their media. Media is attributed
+    /// to the quoted tweet and its author. Costs one request per page of liked tweets.
+    async fn process_quoted(
+        &self,
+        quoted_ids: Vec<NumericId>,
+        options: &ProcessOptions,
+        seen_media: &mut HashSet<StringId>,
+    ) -> Result<ProcessOutcome> {
+        let response = self
+            .api
+            .request(|api| {
+                let mut request = api.get_tweets(quoted_ids.clone());
+                request
+                    .tweet_fields(TWEET_FIELDS)
+                    .expansions([TweetExpansion::AttachmentsMediaKeys])
+                    .media_fields(MEDIA_FIELDS);
+                async move { request.send().await.map(ApiResponse::into_payload) }
+            })
+            .await?;
+        let includes_media: HashMap<_, _> = response
+            .includes()
+            .and_then(|includes| includes.media.clone())
+            .unwrap_or_default()
             .into_iter()
             .map(|media| (media.media_key.clone(), media))
             .collect();
-        let mut image_refs = Vec::new();
-
-        for tweet in liked_tweets.into_iter() {
-            let author_id = tweet.author_id.ok_or_invariant("author id in tweet")?;
-            let guard = self.username_cache.read().await;
-            let username = guard.get(&author_id).cloned();
-            drop(guard);
-            let username = match username {
-                Some(username) => username,
-                None => {
-                    let username = self
-                        .api
-                        .get_user(author_id)
-                        .user_fields([UserField::Username])
-                        .send()
-                        .await?
-                        .into_data()
-                        .ok_or_invariant("username in response")?
-                        .username;
-                    let mut guard = self.username_cache.write().await;
-                    guard.insert(author_id, username);
-                    let username = guard
-                        .get(&author_id)
-                        .cloned()
-                        .expect("just inserted author in cache");
-                    drop(guard);
-                    username
+        let tweets = response.into_data().unwrap_or_default();
+        self.extract_all_media(tweets, &includes_media, options, seen_media)
+            .await
+    }
+
+    /// Look up an author by id, using the cache if possible. Concurrent lookups of the
+    /// same uncached author wait for the first, rather than making their own request.
+    async fn author(&self, author_id: NumericId, options: &ProcessOptions) -> Result<Author> {
+        let guard = self.author_cache.read().await;
+        let author = guard.get(&author_id).cloned();
+        drop(guard);
+        if let Some(author) = author {
+            return Ok(author);
+        }
+
+        let lookup = self
+            .author_lookups
+            .lock()
+            .expect("author lookups lock poisoned")
+            .entry(author_id)
+            .or_default()
+            .clone();
+        let author = lookup
+            .get_or_try_init(|| self.look_up_author(author_id, options))
+            .await?;
+        Ok(author.clone())
+    }
+
+    /// Look up an author by id, and cache them.
+    async fn look_up_author(
+        &self,
+        author_id: NumericId,
+        options: &ProcessOptions,
+    ) -> Result<Author> {
+        let user_fields = user_fields(options);
+        let user = self
+            .api
+            .request(|api| {
+                let mut request = api.get_user(author_id);
+                request.user_fields(user_fields.clone());
+                async move { request.send().await.map(ApiResponse::into_payload) }
+            })
+            .await
+            .map_err(|error| match error {
+                Error::TwitterClient(source) => Error::UserLookupFailed {
+                    id: author_id,
+                    source,
+                },
+                error => error,
+            })?
+            .into_data()
+            .ok_or_invariant("username in response")?;
+        let author = Author::from(user);
+        let mut guard = self.author_cache.write().await;
+        guard.insert(author_id, author.clone());
+        drop(guard);
+        Ok(author)
+    }
+
+    /// Look up and cache the authors of tweets with media to download, ahead of
+    /// processing the pages they are on.
+    ///
+    /// Uncached authors are looked up in batches of up to 100, with at most `concurrency`
+    /// batches in flight. Authors missing from a response, such as suspended accounts,
+    /// are left to be looked up one at a time when processing.
+    pub async fn resolve_authors(
+        &self,
+        pages: &[Page],
+        options: &ProcessOptions,
+        concurrency: usize,
+    ) -> Result<()> {
+        if options.author_ids_as_names {
+            return Ok(());
+        }
+        let mut author_ids = HashSet::new();
+        for page in pages {
+            let includes_media: HashMap<_, _> = page
+                .includes()
+                .and_then(|includes| includes.media.as_ref())
+                .into_iter()
+                .flatten()
+                .map(|media| (media.media_key.clone(), media.clone()))
+                .collect();
+            for tweet in page.data().into_iter().flatten() {
+                if has_wanted_media(tweet, &includes_media, options.video_quality.is_some()) {
+                    author_ids.extend(tweet.author_id);
                 }
-            };
+            }
+        }
+        let guard = self.author_cache.read().await;
+        let uncached: Vec<NumericId> = author_ids
+            .into_iter()
+            .filter(|author_id| !guard.contains_key(author_id))
+            .collect();
+        drop(guard);
+        if uncached.is_empty() {
+            return Ok(());
+        }
 
-            let tweet_ref = TweetRef {
-                username: username.to_owned(),
-                created_at: tweet.created_at.ok_or_invariant("created_at in tweet")?,
-                id: tweet.id,
-            };
+        log::info!("Looking up {} authors", uncached.len());
+        let user_fields = user_fields(options);
+        let batches = futures::stream::iter(uncached.chunks(MAX_USER_LOOKUP))
+            .map(|author_ids| {
+                self.api.request(|api| {
+                    let mut request = api.get_users(author_ids.iter().copied());
+                    request.user_fields(user_fields.clone());
+                    async move { request.send().await.map(ApiResponse::into_payload) }
+                })
+            })
+            .buffer_unordered(concurrency.max(1));
+        futures::pin_mut!(batches);
+        while let Some(users) = batches.try_next().await? {
+            let mut guard = self.author_cache.write().await;
+            for user in users.into_data().into_iter().flatten() {
+                guard.insert(user.id, Author::from(user));
+            }
+        }
+        Ok(())
+    }
 
-            if let Some(attachments) = tweet.attachments {
-                for media_key in attachments
-                    .media_keys
-                    .ok_or_invariant("media_keys in attachments")?
-                    .into_iter()
-                {
-                    if let Some(media) = includes_media.get(&media_key) {
-                        if media.kind == MediaType::Photo {
-                            let url = media.url.as_ref().ok_or_invariant("url in media")?;
-                            let filename = url
-                                .path_segments()
-                                .ok_or_invariant("media url has valid path segments")?
-                                .last()
-                                .ok_or_invariant("media url has no path segments")?;
-                            image_refs.push(ImageRef {
-                                tweet: tweet_ref.clone(),
-                                internal_filename: filename.to_owned(),
-                                url: url.clone(),
-                            })
-                        }
-                    }
+    /// Extract media from tweets, working on up to `options.tweet_concurrency` tweets at
+    /// once. Media is deduplicated against `seen_media` first, one tweet at a time, and
+    /// results are kept in tweet order, so the outcome is the same at any concurrency.
+    async fn extract_all_media(
+        &self,
+        tweets: Vec<Tweet>,
+        includes_media: &HashMap<StringId, Media>,
+        options: &ProcessOptions,
+        seen_media: &mut HashSet<StringId>,
+    ) -> Result<ProcessOutcome> {
+        let mut outcome = ProcessOutcome::default();
+        let mut selected = Vec::new();
+        for tweet in tweets {
+            for media_key in missing_media_keys(&tweet, includes_media) {
+                log::warn!(
+                    "Skipping media {} of tweet {}: missing from response, which may be truncated",
+                    media_key,
+                    tweet.id
+                );
+                outcome.missing_media += 1;
+                outcome.events.push(RunEvent::Skipped {
+                    id: media_key.to_string(),
+                    reason: "media missing from response".to_owned(),
+                });
+            }
+            let media = select_media(&tweet, includes_media, options, seen_media)?;
+            // Tweets without new media don't cost a username lookup.
+            if !media.is_empty() {
+                selected.push((tweet, media));
+            }
+        }
+        futures::stream::iter(selected)
+            .map(|(tweet, media)| self.extract_media(tweet, media, options))
+            .buffered(options.tweet_concurrency.max(1))
+            .try_fold(outcome, |mut outcome, tweet_outcome| {
+                outcome.merge(tweet_outcome);
+                futures::future::ready(Ok(outcome))
+            })
+            .await
+    }
+
+    /// Image references for media attached to a tweet, chosen by `select_media`.
+    async fn extract_media(
+        &self,
+        tweet: Tweet,
+        photos: Vec<(usize, Media)>,
+        options: &ProcessOptions,
+    ) -> Result<ProcessOutcome> {
+        let mut outcome = ProcessOutcome::default();
+        let author_id = tweet.author_id.ok_or_invariant("author id in tweet")?;
+        let username = if options.author_ids_as_names {
+            author_id.to_string()
+        } else {
+            let author = self.author(author_id, options).await?;
+            if let Some((followers, min_followers)) =
+                author.followers.zip(options.min_author_followers)
+            {
+                if followers < min_followers {
+                    log::debug!(
+                        "Skipping tweet {} by '{}' with {} followers",
+                        tweet.id,
+                        author.username,
+                        followers
+                    );
+                    outcome.events.push(RunEvent::Skipped {
+                        id: tweet.id.to_string(),
+                        reason: format!("author has fewer than {min_followers} followers"),
+                    });
+                    return Ok(outcome);
+                }
+            }
+            if let Some(url) = author.profile_image_url {
+                outcome.avatars.insert(
+                    author_id,
+                    AvatarRef {
+                        username: author.username.clone(),
+                        url: full_size_avatar(url),
+                    },
+                );
+            }
+            author.username
+        };
+
+        let created_at = match (tweet.created_at, options.on_missing_date) {
+            (Some(created_at), _) => created_at,
+            (None, on_missing_date) => {
+                outcome.missing_date += 1;
+                if on_missing_date == OnMissingDate::Skip {
+                    log::warn!("Skipping tweet {}: no creation date in response", tweet.id);
+                    outcome.events.push(RunEvent::Skipped {
+                        id: tweet.id.to_string(),
+                        reason: "no creation date in response".to_owned(),
+                    });
+                    return Ok(outcome);
                 }
+                log::warn!(
+                    "Dating tweet {} to the unix epoch: no creation date in response",
+                    tweet.id
+                );
+                time::OffsetDateTime::UNIX_EPOCH
             }
+        };
+        let tweet_ref = TweetRef {
+            username,
+            created_at,
+            id: tweet.id,
+            text: tweet.text,
+            expanded_urls: tweet
+                .entities
+                .and_then(|entities| entities.urls)
+                .into_iter()
+                .flatten()
+                .map(|url| (url.url, url.expanded_url))
+                .collect(),
+            media_count: photos.len(),
+        };
 
-            // Extract image from url in tweet
-            // if let Some(entities) = tweet.entities {
-            //     if let Some(urls) = entities.urls {
-            //         for url in urls.into_iter() {
-            //             if let Some(mut images) = url.images {
-            //                 images.sort_by_key(|image| -(image.height as isize));
-            //                 if let Some(image) = images.into_iter().next() {
-            //                     let mut extension = std::borrow::Cow::Borrowed("jpg");
-            //                     for (key, value) in image.url.query_pairs() {
-            //                         if key == "format" {
-            //                             extension = value;
-            //                         }
-            //                     }
-            //                     image_refs.push(ImageRef {
-            //                         tweet: tweet_ref.clone(),
-            //                         internal_filename: format!("url-link.{extension}"),
-            //                         url: image.url,
-            //                     })
-            //                 };
-            //             }
-            //         }
-            //     }
-            // };
-        }
-
-        Ok(image_refs)
+        for (media_index, media) in photos.into_iter() {
+            let url = match (&media.kind, options.video_quality) {
+                (MediaType::Photo, _) => media.url.as_ref(),
+                (_, video_quality) => media.variants.as_deref().and_then(|variants| {
+                    video::select_variant(
+                        variants,
+                        video_quality.unwrap_or_default(),
+                        options.allow_hls,
+                    )
+                    .and_then(|variant| variant.url.as_ref())
+                }),
+            };
+            let url = match url {
+                Some(url) => url,
+                None => {
+                    log::warn!(
+                        "Skipping media {} in tweet {}: no url in response",
+                        media.media_key,
+                        tweet.id
+                    );
+                    outcome.skipped_no_url += 1;
+                    outcome.events.push(RunEvent::Skipped {
+                        id: media.media_key.to_string(),
+                        reason: "no url in response".to_owned(),
+                    });
+                    continue;
+                }
+            };
+            let filename = Some(url)
+                .filter(|url| matches!(url.scheme(), "http" | "https"))
+                .and_then(|url| url.path_segments())
+                .and_then(|mut segments| segments.next_back())
+                .filter(|filename| !filename.is_empty());
+            let filename = match filename {
+                Some(filename) => filename,
+                None => {
+                    log::warn!(
+                        "Skipping media {} in tweet {}: url '{}' has no filename",
+                        media.media_key,
+                        tweet.id,
+                        url
+                    );
+                    outcome.invalid_url += 1;
+                    outcome.events.push(RunEvent::Skipped {
+                        id: media.media_key.to_string(),
+                        reason: format!("url '{url}' has no filename"),
+                    });
+                    continue;
+                }
+            };
+            let excluded = filename.rsplit_once('.').is_some_and(|(_, extension)| {
+                options
+                    .exclude_extensions
+                    .contains(&extension.to_ascii_lowercase())
+            });
+            if excluded {
+                outcome.excluded_by_extension += 1;
+                continue;
+            }
+            let mut url = url.clone();
+            if media.kind == MediaType::Photo {
+                options.image_size.apply(&mut url);
+            }
+            outcome.image_refs.push(ImageRef {
+                tweet: tweet_ref.clone(),
+                media_key: media.media_key.to_string(),
+                media_index,
+                kind: media.kind.clone(),
+                internal_filename: filename.to_owned(),
+                url,
+                alt_text: media.alt_text.clone(),
+            });
+            if tweet.possibly_sensitive == Some(true) {
+                outcome.sensitive += 1;
+            }
+        }
+
+        // Extract image from url in tweet
+        // if let Some(entities) = tweet.entities {
+        //     if let Some(urls) = entities.urls {
+        //         for url in urls.into_iter() {
+        //             if let Some(mut images) = url.images {
+        //                 images.sort_by_key(|image| -(image.height as isize));
+        //                 if let Some(image) = images.into_iter().next() {
+        //                     let mut extension = std::borrow::Cow::Borrowed("jpg");
+        //                     for (key, value) in image.url.query_pairs() {
+        //                         if key == "format" {
+        //                             extension = value;
+        //                         }
+        //                     }
+        //                     outcome.image_refs.push(ImageRef {
+        //                         tweet: tweet_ref.clone(),
+        //                         internal_filename: format!("url-link.{extension}"),
+        //                         url: image.url,
+        //                     })
+        //                 };
+        //             }
+        //         }
+        //     }
+        // };
+
+        Ok(outcome)
+    }
+}
+
+/// New media attached to a tweet to extract, with its position among the tweet's
+/// attachments. Media already in `seen_media` is left out, so it is only counted once.
+fn select_media(
+    tweet: &Tweet,
+    includes_media: &HashMap<StringId, Media>,
+    options: &ProcessOptions,
+    seen_media: &mut HashSet<StringId>,
+) -> Result<Vec<(usize, Media)>> {
+    let media_keys = match &tweet.attachments {
+        Some(attachments) => attachments
+            .media_keys
+            .as_ref()
+            .ok_or_invariant("media_keys in attachments")?,
+        None => return Ok(Vec::new()),
+    };
+    Ok(media_keys
+        .iter()
+        .enumerate()
+        .filter_map(|(media_index, media_key)| {
+            includes_media
+                .get(media_key)
+                .map(|media| (media_index, media))
+        })
+        .filter(|(_, media)| media.kind == MediaType::Photo || options.video_quality.is_some())
+        .filter(|(_, media)| seen_media.insert(media.media_key.clone()))
+        .map(|(media_index, media)| (media_index, media.clone()))
+        .collect())
+}
+
+/// Media keys a tweet references that aren't in the response's includes.
+fn missing_media_keys<'a>(
+    tweet: &'a Tweet,
+    includes_media: &'a HashMap<StringId, Media>,
+) -> impl Iterator<Item = &'a StringId> {
+    tweet
+        .attachments
+        .iter()
+        .flat_map(|attachments| attachments.media_keys.iter().flatten())
+        .filter(|media_key| !includes_media.contains_key(*media_key))
+}
+
+/// Whether a page has tweets, all created before a time. Tweets without a creation date
+/// are counted as newer, so they aren't missed.
+fn created_before(page: &Page, time: time::OffsetDateTime) -> bool {
+    let tweets = page.data().map(Vec::as_slice).unwrap_or_default();
+    !tweets.is_empty()
+        && tweets
+            .iter()
+            .all(|tweet| tweet.created_at.is_some_and(|created_at| created_at < time))
+}
+
+/// User fields to look up for each author.
+fn user_fields(options: &ProcessOptions) -> Vec<UserField> {
+    let mut user_fields = vec![UserField::Username];
+    if options.author_avatars {
+        user_fields.push(UserField::ProfileImageUrl);
+    }
+    if options.min_author_followers.is_some() {
+        user_fields.push(UserField::PublicMetrics);
+    }
+    user_fields
+}
+
+/// Whether a tweet references any attached media.
+fn has_media_keys(tweet: &Tweet) -> bool {
+    tweet
+        .attachments
+        .as_ref()
+        .and_then(|attachments| attachments.media_keys.as_ref())
+        .is_some_and(|media_keys| !media_keys.is_empty())
+}
+
+/// Whether a tweet has any photos attached.
+pub fn has_photos(tweet: &Tweet, includes_media: &HashMap<StringId, Media>) -> bool {
+    tweet
+        .attachments
+        .as_ref()
+        .and_then(|attachments| attachments.media_keys.as_ref())
+        .into_iter()
+        .flatten()
+        .filter_map(|media_key| includes_media.get(media_key))
+        .any(|media| media.kind == MediaType::Photo)
+}
+
+/// Whether a tweet has media that would be extracted: photos, or any media if videos and
+/// animated GIFs are included too.
+pub fn has_wanted_media(
+    tweet: &Tweet,
+    includes_media: &HashMap<StringId, Media>,
+    include_videos: bool,
+) -> bool {
+    if include_videos {
+        has_media_keys(tweet)
+    } else {
+        has_photos(tweet, includes_media)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use pretty_assertions::assert_eq;
+
+    /// Options that need no API requests to process tweets.
+    fn offline_options() -> ProcessOptions {
+        ProcessOptions {
+            author_ids_as_names: true,
+            ..Default::default()
+        }
+    }
+
+    fn tweet_ids(outcome: &ProcessOutcome) -> Vec<String> {
+        outcome
+            .image_refs
+            .iter()
+            .map(|image_ref| image_ref.tweet.id.to_string())
+            .collect()
+    }
+
+    fn at(rfc3339: &str) -> time::OffsetDateTime {
+        time::OffsetDateTime::parse(rfc3339, &time::format_description::well_known::Rfc3339)
+            .unwrap()
+    }
+
+    /// Likes created just after, exactly at, and just before 2022-11-05T12:00:00Z.
+    fn page_around_boundary() -> Page {
+        fixtures::page(serde_json::json!({
+            "data": [
+                fixtures::tweet("3", "2022-11-05T12:00:01Z", &["3_3"]),
+                fixtures::tweet("2", "2022-11-05T12:00:00Z", &["3_2"]),
+                fixtures::tweet("1", "2022-11-05T11:59:59Z", &["3_1"]),
+            ],
+            "includes": {
+                "media": [
+                    fixtures::photo("3_3", "https://pbs.twimg.com/media/Three.jpg"),
+                    fixtures::photo("3_2", "https://pbs.twimg.com/media/Two.jpg"),
+                    fixtures::photo("3_1", "https://pbs.twimg.com/media/One.jpg"),
+                ],
+            },
+        }))
+    }
+
+    #[tokio::test]
+    async fn created_after_keeps_tweets_at_the_boundary() {
+        let options = ProcessOptions {
+            created_after: Some(at("2022-11-05T12:00:00Z")),
+            ..offline_options()
+        };
+        let outcome = fixtures::bot()
+            .process_page(&page_around_boundary(), &options)
+            .await
+            .unwrap();
+        assert_eq!(tweet_ids(&outcome), ["3", "2"]);
+    }
+
+    #[tokio::test]
+    async fn created_after_skips_quoted_tweets_of_old_likes() {
+        // Fetching the quoted tweet would fail, as the bot's login is unusable.
+        let page = fixtures::page(serde_json::json!({
+            "data": [{
+                "id": "1",
+                "text": "Look at this",
+                "author_id": "1",
+                "created_at": "2022-11-05T11:59:59Z",
+                "referenced_tweets": [{ "type": "quoted", "id": "100" }],
+            }],
+        }));
+        let options = ProcessOptions {
+            created_after: Some(at("2022-11-05T12:00:00Z")),
+            include_quoted_media: true,
+            ..offline_options()
+        };
+        let outcome = fixtures::bot().process_page(&page, &options).await.unwrap();
+        assert!(outcome.image_refs.is_empty());
+    }
+
+    #[tokio::test]
+    async fn all_text_page_without_includes_has_no_media() {
+        let page = fixtures::page(serde_json::json!({
+            "data": [
+                fixtures::tweet("2", "2022-11-05T12:00:00Z", &[]),
+                fixtures::tweet("1", "2022-11-05T11:00:00Z", &[]),
+            ],
+            "meta": { "result_count": 2 },
+        }));
+        let outcome = fixtures::bot()
+            .process_page(&page, &offline_options())
+            .await
+            .unwrap();
+        assert!(outcome.image_refs.is_empty());
+        assert_eq!(outcome.no_media, [NumericId::new(2), NumericId::new(1)]);
+    }
+
+    #[tokio::test]
+    async fn media_without_includes_is_an_invariant_error() {
+        let page = fixtures::page(serde_json::json!({
+            "data": [fixtures::tweet("1", "2022-11-05T12:00:00Z", &["3_1"])],
+        }));
+        let result = fixtures::bot()
+            .process_page(&page, &offline_options())
+            .await;
+        assert!(matches!(
+            result,
+            Err(Error::TwitterApiInvariant("media in includes"))
+        ));
+    }
+
+    #[tokio::test]
+    async fn empty_last_page_has_no_media() {
+        let page = fixtures::page(serde_json::json!({ "meta": { "result_count": 0 } }));
+        let outcome = fixtures::bot()
+            .process_page(&page, &offline_options())
+            .await
+            .unwrap();
+        assert!(outcome.image_refs.is_empty());
+        assert!(outcome.no_media.is_empty());
+    }
+
+    #[tokio::test]
+    async fn media_url_without_filename_is_skipped() {
+        let page = fixtures::page(serde_json::json!({
+            "data": [fixtures::tweet("1", "2022-11-05T12:00:00Z", &["3_1", "3_2", "3_3"])],
+            "includes": {
+                "media": [
+                    fixtures::photo("3_1", "data:image/jpeg;base64,AAAA"),
+                    fixtures::photo("3_2", "https://pbs.twimg.com/"),
+                    fixtures::photo("3_3", "https://pbs.twimg.com/media/Good.jpg"),
+                ],
+            },
+        }));
+        let outcome = fixtures::bot()
+            .process_page(&page, &offline_options())
+            .await
+            .unwrap();
+        assert_eq!(outcome.invalid_url, 2);
+        let filenames: Vec<&str> = outcome
+            .image_refs
+            .iter()
+            .map(|image_ref| image_ref.internal_filename.as_str())
+            .collect();
+        assert_eq!(filenames, ["Good.jpg"]);
+    }
+
+    #[test]
+    fn created_before_boundary() {
+        let page = page_around_boundary();
+        assert!(created_before(&page, at("2022-11-05T12:00:02Z")));
+        assert!(!created_before(&page, at("2022-11-05T12:00:01Z")));
+        assert!(!created_before(&page, at("2022-11-05T11:59:59Z")));
+    }
+
+    #[test]
+    fn created_before_empty_page() {
+        let page = fixtures::page(serde_json::json!({}));
+        assert!(!created_before(&page, at("2022-11-05T12:00:00Z")));
+    }
+
+    fn video_only_page() -> Page {
+        fixtures::page(serde_json::json!({
+            "data": [fixtures::tweet("1", "2022-11-05T12:00:00Z", &["7_1"])],
+            "includes": {
+                "media": [fixtures::video("7_1", "https://video.twimg.com/One.mp4")],
+            },
+        }))
+    }
+
+    #[tokio::test]
+    async fn video_only_tweet_has_no_media_without_videos() {
+        let outcome = fixtures::bot()
+            .process_page(&video_only_page(), &offline_options())
+            .await
+            .unwrap();
+        assert!(outcome.image_refs.is_empty());
+        assert_eq!(outcome.no_media, [NumericId::new(1)]);
+    }
+
+    #[tokio::test]
+    async fn video_only_tweet_has_media_with_videos() {
+        let options = ProcessOptions {
+            video_quality: Some(VideoQuality::default()),
+            ..offline_options()
+        };
+        let outcome = fixtures::bot()
+            .process_page(&video_only_page(), &options)
+            .await
+            .unwrap();
+        assert_eq!(tweet_ids(&outcome), ["1"]);
+        assert!(outcome.no_media.is_empty());
+    }
+
+    /// A bot that returns these responses in order instead of calling Twitter.
+    fn scripted_bot(responses: Vec<crate::api::Scripted>) -> Bot {
+        let api = RateLimitedApi::new(
+            fixtures::token(),
+            RateLimitConfig::default(),
+            None,
+            Arc::new(SystemClock),
+        )
+        .unwrap()
+        .scripted(responses);
+        Bot {
+            api,
+            author_cache: Default::default(),
+            author_lookups: Default::default(),
+        }
+    }
+
+    fn logged_in_user() -> crate::api::Scripted {
+        Ok(serde_json::json!({
+            "data": { "id": "42", "name": "Magpie", "username": "magpie" },
+        }))
+    }
+
+    fn last_page_of_likes() -> crate::api::Scripted {
+        Ok(serde_json::json!({
+            "data": [fixtures::tweet("1", "2022-11-05T12:00:00Z", &[])],
+            "meta": { "result_count": 1 },
+        }))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn logged_in_user_retries_transient_failure() {
+        let bot = scripted_bot(vec![
+            Err(fixtures::api_error(StatusCode::SERVICE_UNAVAILABLE)),
+            logged_in_user(),
+        ]);
+        let user = bot.logged_in_user().await.unwrap();
+        assert_eq!(user.username, "magpie");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn first_page_of_likes_retries_transient_failures() {
+        let bot = scripted_bot(vec![
+            Err(fixtures::api_error(StatusCode::SERVICE_UNAVAILABLE)),
+            logged_in_user(),
+            Err(fixtures::api_error(StatusCode::BAD_GATEWAY)),
+            last_page_of_likes(),
+        ]);
+        let pages: Vec<Page> = bot
+            .fetch_liked_tweets(FetchOptions::default())
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(pages.len(), 1);
+        assert_eq!(pages[0].data().unwrap()[0].id, NumericId::new(1));
+    }
+
+    #[tokio::test]
+    async fn concurrent_tweets_by_uncached_author_look_them_up_once() {
+        // Only one response is scripted, so a second lookup would panic.
+        let bot = scripted_bot(vec![Ok(serde_json::json!({
+            "data": { "id": "1", "name": "Magpie", "username": "magpie" },
+        }))]);
+        let page = fixtures::page(serde_json::json!({
+            "data": [
+                fixtures::tweet("3", "2022-11-05T12:00:00Z", &["3_3"]),
+                fixtures::tweet("2", "2022-11-05T12:00:00Z", &["3_2"]),
+                fixtures::tweet("1", "2022-11-05T12:00:00Z", &["3_1"]),
+            ],
+            "includes": {
+                "media": [
+                    fixtures::photo("3_3", "https://pbs.twimg.com/media/Three.jpg"),
+                    fixtures::photo("3_2", "https://pbs.twimg.com/media/Two.jpg"),
+                    fixtures::photo("3_1", "https://pbs.twimg.com/media/One.jpg"),
+                ],
+            },
+        }));
+        let options = ProcessOptions {
+            tweet_concurrency: 3,
+            ..Default::default()
+        };
+        let outcome = bot.process_page(&page, &options).await.unwrap();
+        let usernames: Vec<&str> = outcome
+            .image_refs
+            .iter()
+            .map(|image_ref| image_ref.tweet.username.as_str())
+            .collect();
+        assert_eq!(usernames, ["magpie", "magpie", "magpie"]);
+        assert_eq!(bot.usernames().await.len(), 1);
     }
 }
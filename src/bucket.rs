@@ -0,0 +1,113 @@
+//! Output to an S3-compatible bucket, rather than the local output directory.
+
+use crate::download::{self, OutputSink};
+use bytes::Bytes;
+use futures::{Stream, TryStreamExt};
+use thiserror::Error;
+use tokio::io::AsyncRead;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Invalid bucket uri '{uri}', expected 's3://bucket/prefix'")]
+    Uri { uri: String },
+    #[error("Bucket configuration is invalid")]
+    Config(#[source] s3::error::S3Error),
+    #[error("Failed to upload '{key}' to bucket")]
+    Upload {
+        key: String,
+        source: s3::error::S3Error,
+    },
+    #[error("Bucket responded with {status} uploading '{key}'")]
+    Status { key: String, status: u16 },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Split an `s3://bucket/prefix` uri into the bucket name and key prefix. The prefix may
+/// be empty.
+fn split_uri(uri: &str) -> Option<(&str, &str)> {
+    let path = uri.strip_prefix("s3://")?;
+    let (name, prefix) = path.split_once('/').unwrap_or((path, ""));
+    (!name.is_empty()).then_some((name, prefix.trim_matches('/')))
+}
+
+/// Check a bucket uri given by the user.
+pub fn parse_uri(uri: &str) -> std::result::Result<String, String> {
+    match split_uri(uri) {
+        Some(_) => Ok(uri.to_owned()),
+        None => Err(format!("expected 's3://bucket/prefix', got '{uri}'")),
+    }
+}
+
+/// A bucket and key prefix that media is uploaded under.
+#[derive(Debug)]
+pub struct Bucket {
+    bucket: s3::Bucket,
+    prefix: String,
+}
+
+impl Bucket {
+    /// Connect to the bucket named by an `s3://bucket/prefix` uri.
+    ///
+    /// Credentials and region are read from the standard AWS environment variables and
+    /// profile. Set `AWS_ENDPOINT` to use another S3-compatible service, which is then
+    /// addressed with path-style urls.
+    pub fn from_uri(uri: &str) -> Result<Self> {
+        let (name, prefix) = split_uri(uri).ok_or_else(|| Error::Uri {
+            uri: uri.to_owned(),
+        })?;
+        let region = s3::Region::from_default_env().map_err(|error| Error::Config(error.into()))?;
+        let custom_endpoint = matches!(region, s3::Region::Custom { .. });
+        let credentials =
+            s3::creds::Credentials::default().map_err(|error| Error::Config(error.into()))?;
+        let mut bucket = s3::Bucket::new(name, region, credentials).map_err(Error::Config)?;
+        if custom_endpoint {
+            bucket = bucket.with_path_style();
+        }
+        Ok(Self {
+            bucket,
+            prefix: prefix.to_owned(),
+        })
+    }
+
+    /// Key an output filename is uploaded to.
+    pub fn key(&self, filename: &str) -> String {
+        if self.prefix.is_empty() {
+            filename.to_owned()
+        } else {
+            format!("{}/{}", self.prefix, filename)
+        }
+    }
+
+    /// Upload a file, streaming it in parts rather than buffering it in memory.
+    pub async fn put<R: AsyncRead + Unpin>(&self, filename: &str, reader: &mut R) -> Result<()> {
+        let key = self.key(filename);
+        let status = self
+            .bucket
+            .put_object_stream(reader, &key)
+            .await
+            .map_err(|source| Error::Upload {
+                key: key.clone(),
+                source,
+            })?;
+        if !(200..300).contains(&status) {
+            return Err(Error::Status { key, status });
+        }
+        Ok(())
+    }
+}
+
+/// Media is uploaded in parts as its body arrives, so large files aren't held in memory.
+impl OutputSink for Bucket {
+    async fn write_media<S>(&self, name: &str, body: S) -> std::result::Result<(), download::Error>
+    where
+        S: Stream<Item = reqwest::Result<Bytes>> + Send,
+    {
+        let body = body.map_err(std::io::Error::other);
+        futures::pin_mut!(body);
+        let mut reader = tokio_util::io::StreamReader::new(body);
+        self.put(name, &mut reader)
+            .await
+            .map_err(download::Error::Bucket)
+    }
+}
@@ -0,0 +1,183 @@
+//! Tweet captions written alongside downloaded media.
+
+use crate::bot::TweetRef;
+use crate::permalink::Permalinks;
+
+const TCO_PREFIX: &str = "https://t.co/";
+
+/// How to treat `t.co` short links in captions.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TcoLinks {
+    /// Leave links as they are.
+    #[default]
+    Keep,
+    /// Remove links, such as the link Twitter appends for attached media.
+    Strip,
+    /// Replace links with the url they expand to, where known.
+    Resolve,
+}
+
+/// Replace each `t.co` short link in tweet text with the result of `replace`.
+fn replace_tco_links<'a>(text: &'a str, replace: impl Fn(&'a str) -> &'a str) -> String {
+    let mut replaced = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find(TCO_PREFIX) {
+        replaced.push_str(&rest[..start]);
+        let link = &rest[start..];
+        let end = link.find(char::is_whitespace).unwrap_or(link.len());
+        replaced.push_str(replace(&link[..end]));
+        rest = &link[end..];
+    }
+    replaced.push_str(rest);
+    replaced
+}
+
+/// Remove `t.co` short links from tweet text, such as the link Twitter appends for
+/// attached media.
+///
+/// The spaces around each removed link are collapsed, so the words either side of it
+/// are separated by a single space, or by nothing at the start or end of a line.
+pub fn strip_tco_links(text: &str) -> String {
+    let is_space = |c: char| c == ' ' || c == '\t';
+    let mut stripped = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find(TCO_PREFIX) {
+        stripped.push_str(rest[..start].trim_end_matches(is_space));
+        let link = &rest[start..];
+        let end = link.find(char::is_whitespace).unwrap_or(link.len());
+        rest = link[end..].trim_start_matches(is_space);
+        let line_start = stripped.is_empty() || stripped.ends_with('\n');
+        let line_end = rest.is_empty() || rest.starts_with(['\r', '\n']);
+        if !line_start && !line_end {
+            stripped.push(' ');
+        }
+    }
+    stripped.push_str(rest);
+    stripped.trim_end().to_owned()
+}
+
+/// Replace `t.co` short links in tweet text with their expanded urls from the tweet's
+/// entities. Links without an expansion are left as they are.
+pub fn resolve_tco_links(tweet: &TweetRef) -> String {
+    replace_tco_links(&tweet.text, |link| {
+        tweet
+            .expanded_urls
+            .get(link)
+            .map(String::as_str)
+            .unwrap_or(link)
+    })
+}
+
+/// Contents of a caption sidecar file: the tweet text, followed by its permalink.
+pub fn sidecar(tweet: &TweetRef, permalinks: &Permalinks, tco: TcoLinks) -> String {
+    let text = match tco {
+        TcoLinks::Keep => tweet.text.clone(),
+        TcoLinks::Strip => strip_tco_links(&tweet.text),
+        TcoLinks::Resolve => resolve_tco_links(tweet),
+    };
+    format!("{}\n\n{}\n", text, permalinks.link(tweet))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use pretty_assertions::assert_eq;
+
+    fn tweet(text: &str) -> TweetRef {
+        TweetRef {
+            text: text.to_owned(),
+            ..fixtures::image_ref().tweet
+        }
+    }
+
+    #[test]
+    fn sidecar_keeps_text_and_links_to_tweet() {
+        assert_eq!(
+            sidecar(
+                &tweet("A shiny thing"),
+                &Permalinks::default(),
+                TcoLinks::Keep
+            ),
+            "A shiny thing\n\nhttps://twitter.com/magpie/status/1588888888888888888\n"
+        );
+    }
+
+    #[test]
+    fn sidecar_keeps_unicode_and_newlines() {
+        assert_eq!(
+            sidecar(
+                &tweet("Ein glänzendes Ding ✨\n\nかささぎ 🐦‍⬛\r\nline three"),
+                &Permalinks::default(),
+                TcoLinks::Keep
+            ),
+            "Ein glänzendes Ding ✨\n\nかささぎ 🐦‍⬛\r\nline three\n\nhttps://twitter.com/magpie/status/1588888888888888888\n"
+        );
+    }
+
+    #[test]
+    fn strip_tco_links_keeps_unicode_and_newlines() {
+        assert_eq!(
+            strip_tco_links("Glänzend ✨\nzweite Zeile https://t.co/AbCdEf123"),
+            "Glänzend ✨\nzweite Zeile"
+        );
+    }
+
+    fn tweet_with_links() -> TweetRef {
+        TweetRef {
+            expanded_urls: [
+                ("https://t.co/first", "https://example.com/one"),
+                ("https://t.co/second", "https://example.org/two?page=2"),
+            ]
+            .into_iter()
+            .map(|(link, expanded)| (link.to_owned(), expanded.to_owned()))
+            .collect(),
+            ..tweet("See https://t.co/first and https://t.co/second\nhttps://t.co/media")
+        }
+    }
+
+    #[test]
+    fn resolve_multiple_tco_links() {
+        assert_eq!(
+            resolve_tco_links(&tweet_with_links()),
+            "See https://example.com/one and https://example.org/two?page=2\nhttps://t.co/media"
+        );
+    }
+
+    #[test]
+    fn strip_multiple_tco_links() {
+        assert_eq!(strip_tco_links(&tweet_with_links().text), "See and");
+    }
+
+    #[test]
+    fn strip_tco_links_at_line_start() {
+        assert_eq!(
+            strip_tco_links("https://t.co/first shiny\nhttps://t.co/second more"),
+            "shiny\nmore"
+        );
+    }
+
+    #[test]
+    fn resolve_repeated_tco_link() {
+        let tweet = TweetRef {
+            text: "https://t.co/first and again https://t.co/first".to_owned(),
+            ..tweet_with_links()
+        };
+        assert_eq!(
+            resolve_tco_links(&tweet),
+            "https://example.com/one and again https://example.com/one"
+        );
+    }
+
+    #[test]
+    fn sidecar_resolves_tco_links() {
+        assert_eq!(
+            sidecar(
+                &tweet_with_links(),
+                &Permalinks::default(),
+                TcoLinks::Resolve
+            ),
+            "See https://example.com/one and https://example.org/two?page=2\nhttps://t.co/media\n\nhttps://twitter.com/magpie/status/1588888888888888888\n"
+        );
+    }
+}
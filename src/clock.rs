@@ -0,0 +1,29 @@
+//! The current time, as a trait so time based behaviour can be made deterministic.
+
+use std::fmt::Debug;
+use time::OffsetDateTime;
+
+/// A source of the current time.
+pub trait Clock: Debug + Send + Sync {
+    fn now(&self) -> OffsetDateTime;
+}
+
+/// The system clock, used unless another is given.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> OffsetDateTime {
+        OffsetDateTime::now_utc()
+    }
+}
+
+/// A clock stopped at a fixed time, such as for tests.
+#[derive(Debug, Clone, Copy)]
+pub struct FixedClock(pub OffsetDateTime);
+
+impl Clock for FixedClock {
+    fn now(&self) -> OffsetDateTime {
+        self.0
+    }
+}
@@ -0,0 +1,137 @@
+//! Concurrency limits that adapt to how requests are going.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+/// Number of recent results considered when adjusting the limit.
+const WINDOW: usize = 20;
+
+/// Fraction of failures in the window above which the limit is halved.
+const BACKOFF_RATE: f64 = 0.2;
+
+/// A named combination of concurrency settings, for runs that don't tune each one.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Preset {
+    /// Few requests at once, staying well under rate limits on slow or shared
+    /// connections.
+    Conservative,
+    /// The defaults, suiting most runs.
+    #[default]
+    Balanced,
+    /// Many requests at once, for the fastest runs on a good connection. Large first
+    /// runs may wait out more rate limits.
+    Aggressive,
+}
+
+/// Concurrency settings chosen by a [`Preset`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tuning {
+    /// Images downloaded in parallel.
+    pub download_n: usize,
+    /// HEAD requests made in parallel when prefetching sizes.
+    pub prefetch_n: usize,
+    /// Tweets in each page that media is extracted from in parallel.
+    pub process_tweets_n: usize,
+    /// Batches of authors looked up in parallel.
+    pub user_lookup_n: usize,
+}
+
+impl Preset {
+    pub fn tuning(self) -> Tuning {
+        match self {
+            Self::Conservative => Tuning {
+                download_n: 2,
+                prefetch_n: 8,
+                process_tweets_n: 1,
+                user_lookup_n: 1,
+            },
+            Self::Balanced => Tuning {
+                download_n: 8,
+                prefetch_n: 32,
+                process_tweets_n: 1,
+                user_lookup_n: 2,
+            },
+            Self::Aggressive => Tuning {
+                download_n: 32,
+                prefetch_n: 64,
+                process_tweets_n: 8,
+                user_lookup_n: 4,
+            },
+        }
+    }
+}
+
+/// A concurrency limit using additive-increase/multiplicative-decrease.
+///
+/// The limit halves when too many recent requests fail, and grows by one after a full
+/// window of requests succeeds, up to the maximum it started at.
+pub struct AdaptiveLimit {
+    permits: Semaphore,
+    max: usize,
+    state: Mutex<State>,
+}
+
+struct State {
+    limit: usize,
+    /// Permits to discard as they are released, to bring the limit down.
+    excess: usize,
+    recent: VecDeque<bool>,
+}
+
+impl AdaptiveLimit {
+    pub fn new(max: usize) -> Self {
+        Self {
+            permits: Semaphore::new(max),
+            max,
+            state: Mutex::new(State {
+                limit: max,
+                excess: 0,
+                recent: VecDeque::with_capacity(WINDOW),
+            }),
+        }
+    }
+
+    /// Wait for a slot to make a request in.
+    pub async fn acquire(&self) -> SemaphorePermit<'_> {
+        self.permits
+            .acquire()
+            .await
+            .expect("limit semaphore closed")
+    }
+
+    /// Give back a slot, recording whether the request made in it succeeded.
+    pub fn release(&self, permit: SemaphorePermit<'_>, success: bool) {
+        let mut state = self.state.lock().expect("limit lock poisoned");
+        if state.excess > 0 {
+            state.excess -= 1;
+            permit.forget();
+        } else {
+            drop(permit);
+        }
+
+        if state.recent.len() == WINDOW {
+            state.recent.pop_front();
+        }
+        state.recent.push_back(success);
+        let failures = state.recent.iter().filter(|success| !**success).count();
+
+        if failures as f64 / WINDOW as f64 > BACKOFF_RATE && state.limit > 1 {
+            let decrease = state.limit - state.limit / 2;
+            state.limit -= decrease;
+            state.excess += decrease;
+            state.recent.clear();
+            log::debug!("Reducing concurrency to {}", state.limit);
+        } else if state.recent.len() == WINDOW && failures == 0 && state.limit < self.max {
+            state.limit += 1;
+            // Cancel out permits still waiting to be discarded before adding new ones.
+            if state.excess > 0 {
+                state.excess -= 1;
+            } else {
+                self.permits.add_permits(1);
+            }
+            state.recent.clear();
+            log::debug!("Increasing concurrency to {}", state.limit);
+        }
+    }
+}
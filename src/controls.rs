@@ -0,0 +1,147 @@
+//! Keyboard controls for pausing and quitting downloads from an interactive terminal.
+
+use crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// How often the key reader checks whether it should stop.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Whether downloads should be started.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Running,
+    Paused,
+    /// No more downloads should start, though those in progress may finish.
+    Quit,
+}
+
+/// Shared download state, changed by key presses.
+#[derive(Debug, Clone)]
+pub struct Controls {
+    state: watch::Receiver<State>,
+}
+
+impl Controls {
+    /// Controls that never pause or quit, for runs without a terminal.
+    pub fn fixed() -> Self {
+        let (_, state) = watch::channel(State::Running);
+        Self { state }
+    }
+
+    /// Wait while downloads are paused. Returns whether the next download should start,
+    /// which is false once quit.
+    pub async fn proceed(&self) -> bool {
+        let mut state = self.state.clone();
+        loop {
+            let current = *state.borrow_and_update();
+            match current {
+                State::Running => return true,
+                State::Quit => return false,
+                State::Paused => {}
+            }
+            if state.changed().await.is_err() {
+                // The key reader stopped, so nothing can resume or quit any more.
+                return *state.borrow() != State::Quit;
+            }
+        }
+    }
+
+    /// Whether quitting was requested.
+    pub fn quit(&self) -> bool {
+        *self.state.borrow() == State::Quit
+    }
+}
+
+/// End of line for output written to the terminal. While keys are read in raw mode, the
+/// terminal no longer returns to the start of the line on `\n`, so a `\r` is needed too.
+pub fn line_ending() -> &'static str {
+    if crossterm::terminal::is_raw_mode_enabled().unwrap_or(false) {
+        "\r\n"
+    } else {
+        "\n"
+    }
+}
+
+/// Reads key presses with the terminal in raw mode, until dropped.
+///
+/// Space pauses and resumes starting downloads, and `q` or ctrl-c quits once those in
+/// progress finish. The terminal is restored when the reader is dropped. Log lines
+/// written meanwhile should end with [`line_ending`].
+pub struct KeyReader {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl KeyReader {
+    pub fn start() -> std::io::Result<(Self, Controls)> {
+        crossterm::terminal::enable_raw_mode()?;
+        let (sender, state) = watch::channel(State::Running);
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = std::thread::spawn({
+            let stop = stop.clone();
+            move || read_keys(&sender, &stop)
+        });
+        let reader = Self {
+            stop,
+            thread: Some(thread),
+        };
+        Ok((reader, Controls { state }))
+    }
+}
+
+impl Drop for KeyReader {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        if let Err(error) = crossterm::terminal::disable_raw_mode() {
+            log::warn!("Failed to restore terminal: {error}");
+        }
+    }
+}
+
+fn read_keys(sender: &watch::Sender<State>, stop: &AtomicBool) {
+    while !stop.load(Ordering::Relaxed) {
+        let key = match event::poll(POLL_INTERVAL).and_then(|ready| {
+            if ready {
+                event::read().map(Some)
+            } else {
+                Ok(None)
+            }
+        }) {
+            Ok(Some(Event::Key(key))) if key.kind == KeyEventKind::Press => key,
+            Ok(_) => continue,
+            Err(error) => {
+                log::warn!("Stopped reading keys: {error}");
+                return;
+            }
+        };
+        let quit = key.code == KeyCode::Char('q')
+            || (key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL));
+        let current = *sender.borrow();
+        let next = match (current, key.code) {
+            (State::Quit, _) => State::Quit,
+            _ if quit => {
+                log::info!("Quitting once downloads in progress finish");
+                State::Quit
+            }
+            (State::Running, KeyCode::Char(' ')) => {
+                log::info!("Paused, press space to resume");
+                State::Paused
+            }
+            (State::Paused, KeyCode::Char(' ')) => {
+                log::info!("Resumed");
+                State::Running
+            }
+            (state, _) => state,
+        };
+        if next != current {
+            sender.send_replace(next);
+        }
+    }
+}
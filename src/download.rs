@@ -1,25 +1,770 @@
+use crate::bot::ImageSize;
+use crate::bucket::{self, Bucket};
+use crate::manifest::Checksum;
+use bytes::Bytes;
+use futures::{Stream, StreamExt, TryStreamExt};
+use reqwest::header::{
+    HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH,
+    LAST_MODIFIED,
+};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::future::Future;
 use std::io::Write;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("Local file error")]
-    File(std::io::Error),
+    File(#[source] std::io::Error),
     #[error("Remote file error")]
-    Remote(reqwest::Error),
+    Remote(#[source] reqwest::Error),
+    #[error("Server responded with {status}: {body}")]
+    Status { status: StatusCode, body: String },
+    #[error("Server responded with '{content_type}' rather than media: {body}")]
+    ContentType { content_type: String, body: String },
+    #[error("Bucket error")]
+    Bucket(#[source] bucket::Error),
 }
 
-pub async fn file(client: &reqwest::Client, url: url::Url, path: &Path) -> Result<(), Error> {
-    let mut file = std::fs::File::create(path).map_err(Error::File)?;
-    let bytes = &client
-        .get(url)
-        .send()
+impl Error {
+    /// HTTP status of a failed response from the remote server, if there was one.
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            Self::Remote(error) => error.status(),
+            Self::Status { status, .. } => Some(*status),
+            Self::File(_) | Self::ContentType { .. } | Self::Bucket(_) => None,
+        }
+    }
+}
+
+/// Maximum length of a response body included in an error message.
+const BODY_SNIPPET_LEN: usize = 300;
+
+/// The start of an error response body, for debugging.
+async fn body_snippet(response: reqwest::Response) -> String {
+    match response.bytes().await {
+        Ok(bytes) => {
+            let snippet = String::from_utf8_lossy(&bytes[..bytes.len().min(BODY_SNIPPET_LEN)]);
+            let ellipsis = if bytes.len() > BODY_SNIPPET_LEN {
+                "..."
+            } else {
+                ""
+            };
+            format!("{}{}", snippet.trim(), ellipsis)
+        }
+        Err(error) => format!("<failed to read body: {error}>"),
+    }
+}
+
+/// Whether a content type is a document, such as an error page, rather than media.
+fn is_document(content_type: &str) -> bool {
+    content_type.starts_with("text/") || content_type.starts_with("application/json")
+}
+
+/// Cache validators returned by the server for a previous download.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Validators {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub etag: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_modified: Option<String>,
+}
+
+impl Validators {
+    fn from_headers(headers: &HeaderMap) -> Self {
+        let header = |name| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(ToOwned::to_owned)
+        };
+        Self {
+            etag: header(ETAG),
+            last_modified: header(LAST_MODIFIED),
+        }
+    }
+}
+
+/// Outcome of a conditional download.
+#[derive(Debug, Clone)]
+pub enum Fetched {
+    /// The file was written, and the server returned these validators.
+    Downloaded {
+        validators: Validators,
+        checksum: Checksum,
+    },
+    /// The server reported the remote file unchanged, and the local file was left alone.
+    NotModified,
+}
+
+/// Where downloaded media is written.
+#[derive(Debug)]
+pub enum Sink {
+    /// Files in the local output directory.
+    Local,
+    /// Objects in an S3-compatible bucket, keyed by the same names as local files.
+    Bucket(Box<Bucket>),
+}
+
+/// Parse a header given as `Name: Value`.
+pub fn parse_header(header: &str) -> Result<(HeaderName, HeaderValue), String> {
+    let (name, value) = header
+        .split_once(':')
+        .ok_or_else(|| format!("expected 'Name: Value', got '{header}'"))?;
+    let name = name
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid header name '{}'", name.trim()))?;
+    let value = value
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid header value '{}'", value.trim()))?;
+    Ok((name, value))
+}
+
+/// Query parameters that select which media is served, such as `format=jpg&name=orig`.
+const MEANINGFUL_QUERY_PARAMS: [&str; 2] = ["format", "name"];
+
+/// Remove query parameters from a media url, except those that select which media is
+/// served. The query is removed entirely if nothing is left.
+pub fn strip_query(url: &mut url::Url) {
+    let kept: Vec<(String, String)> = url
+        .query_pairs()
+        .filter(|(key, _)| MEANINGFUL_QUERY_PARAMS.contains(&key.as_ref()))
+        .map(|(key, value)| (key.into_owned(), value.into_owned()))
+        .collect();
+    if kept.is_empty() {
+        url.set_query(None);
+    } else {
+        url.query_pairs_mut().clear().extend_pairs(kept);
+    }
+}
+
+/// What to do when a download's target path already holds a file that wasn't downloaded
+/// from the same media.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum OnCollision {
+    /// Leave the existing file, and don't download the media.
+    Skip,
+    /// Replace the existing file.
+    Overwrite,
+    /// Download to a new name, with a counter appended, such as "name (1).jpg".
+    #[default]
+    Rename,
+}
+
+/// A filename with a counter added before its extension, such as "name (1).jpg".
+pub fn numbered_filename(filename: &str, counter: usize) -> String {
+    let (directory, name) = match filename.rsplit_once('/') {
+        Some((directory, name)) => (Some(directory), name),
+        None => (None, filename),
+    };
+    let name = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => format!("{stem} ({counter}).{extension}"),
+        _ => format!("{name} ({counter})"),
+    };
+    match directory {
+        Some(directory) => format!("{directory}/{name}"),
+        None => name,
+    }
+}
+
+/// The filename to download media to, or `None` to skip it. `taken` says whether a name
+/// already holds, or is claimed for, a file other than this media, in which case
+/// `on_collision` decides what happens. Renaming picks the first numbered name that isn't
+/// taken, so media renamed by an earlier run is written to the same name again.
+pub fn resolve_collision(
+    filename: &str,
+    on_collision: OnCollision,
+    taken: impl Fn(&str) -> bool,
+) -> Option<String> {
+    if !taken(filename) {
+        return Some(filename.to_owned());
+    }
+    match on_collision {
+        OnCollision::Skip => None,
+        OnCollision::Overwrite => Some(filename.to_owned()),
+        OnCollision::Rename => (1..)
+            .map(|counter| numbered_filename(filename, counter))
+            .find(|numbered| !taken(numbered)),
+    }
+}
+
+/// Which redirects downloads follow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Redirects {
+    /// Maximum number of redirects to follow for one download.
+    pub max: usize,
+    /// Only follow redirects to the host the download started at.
+    pub same_host_only: bool,
+}
+
+impl Default for Redirects {
+    /// Matches the default policy of `reqwest`.
+    fn default() -> Self {
+        Self {
+            max: 10,
+            same_host_only: false,
+        }
+    }
+}
+
+impl Redirects {
+    fn policy(self) -> reqwest::redirect::Policy {
+        reqwest::redirect::Policy::custom(move |attempt| {
+            let origin = attempt.previous().first().and_then(|url| url.host_str());
+            if attempt.previous().len() > self.max {
+                let message = format!("stopped after {} redirects", self.max);
+                attempt.error(message)
+            } else if self.same_host_only && attempt.url().host_str() != origin {
+                let message = format!(
+                    "refused redirect to another host '{}'",
+                    attempt.url().host_str().unwrap_or_default()
+                );
+                attempt.error(message)
+            } else {
+                attempt.follow()
+            }
+        })
+    }
+}
+
+/// A client for downloading media.
+#[derive(Debug, Clone)]
+pub struct Client {
+    http: reqwest::Client,
+    /// Artificial delay before each chunk of a response body.
+    ///
+    /// Contributor note: this exists to reproduce slow networks deterministically when
+    /// working on throttling, progress reporting and timeouts, via the hidden
+    /// `--simulate-slow-ms` flag.
+    chunk_delay: Option<Duration>,
+}
+
+impl Client {
+    /// Delay every chunk of every download by `delay`, to simulate a slow network.
+    pub fn simulate_slow(mut self, delay: Duration) -> Self {
+        self.chunk_delay = Some(delay);
+        self
+    }
+}
+
+/// Build a client for downloading media, sending the given headers with every request.
+///
+/// Media files are already compressed binaries, so transfer compression is disabled
+/// rather than asking the CDN to compress them again.
+pub fn client(
+    headers: impl IntoIterator<Item = (HeaderName, HeaderValue)>,
+    redirects: Redirects,
+) -> Client {
+    let http = reqwest::Client::builder()
+        .default_headers(headers.into_iter().collect())
+        .redirect(redirects.policy())
+        .no_brotli()
+        .no_deflate()
+        .no_gzip()
+        .build()
+        .expect("download client configuration invalid");
+    Client {
+        http,
+        chunk_delay: None,
+    }
+}
+
+/// Find the size of a remote file with a `HEAD` request.
+///
+/// Returns `None` if the server rejects the request or doesn't report a length, since
+/// some hosts don't support `HEAD` at all.
+pub async fn content_length(client: &Client, url: url::Url) -> Option<u64> {
+    match client.http.head(url.clone()).send().await {
+        Ok(response) if response.status().is_success() => response
+            .headers()
+            .get(reqwest::header::CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok()),
+        Ok(response) => {
+            log::debug!("HEAD '{}' returned {}", url, response.status());
+            None
+        }
+        Err(error) => {
+            log::debug!("HEAD '{}' failed: {}", url, error);
+            None
+        }
+    }
+}
+
+/// Check a response is media, rather than an error or document.
+async fn check_response(response: reqwest::Response) -> Result<reqwest::Response, Error> {
+    let status = response.status();
+    if status.is_client_error() || status.is_server_error() {
+        let body = body_snippet(response).await;
+        return Err(Error::Status { status, body });
+    }
+    let content_type = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned);
+    if let Some(content_type) = content_type.filter(|content_type| is_document(content_type)) {
+        let body = body_snippet(response).await;
+        return Err(Error::ContentType { content_type, body });
+    }
+    Ok(response)
+}
+
+/// Somewhere downloaded media can be written, such as a local directory or a bucket.
+pub trait OutputSink {
+    /// Write media under `name`, a `/` separated relative path, as its body arrives.
+    fn write_media<S>(&self, name: &str, body: S) -> impl Future<Output = Result<(), Error>> + Send
+    where
+        S: Stream<Item = reqwest::Result<Bytes>> + Send;
+}
+
+/// Removes a partially written file, unless it is persisted first.
+struct PartFile {
+    path: PathBuf,
+    persisted: bool,
+}
+
+impl Drop for PartFile {
+    fn drop(&mut self) {
+        if !self.persisted {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Media is written to files in a directory. Each file is written as `<name>.part`, and
+/// only renamed once its body is complete, so no partial files are left behind.
+impl OutputSink for Path {
+    async fn write_media<S>(&self, name: &str, body: S) -> Result<(), Error>
+    where
+        S: Stream<Item = reqwest::Result<Bytes>> + Send,
+    {
+        let path = self.join(name);
+        let mut part = path.clone().into_os_string();
+        part.push(".part");
+        let mut part = PartFile {
+            path: PathBuf::from(part),
+            persisted: false,
+        };
+        let mut file = std::fs::File::create(&part.path).map_err(Error::File)?;
+        futures::pin_mut!(body);
+        while let Some(chunk) = body.try_next().await.map_err(Error::Remote)? {
+            file.write_all(&chunk).map_err(Error::File)?;
+        }
+        drop(file);
+        std::fs::rename(&part.path, &path).map_err(Error::File)?;
+        part.persisted = true;
+        Ok(())
+    }
+}
+
+/// Writes media to stdout, for piping a single file into another program.
+#[derive(Debug, Clone, Copy)]
+pub struct Stdout;
+
+/// Media is written to stdout as its body arrives. The name is ignored, so only one file
+/// should be written.
+impl OutputSink for Stdout {
+    async fn write_media<S>(&self, _name: &str, body: S) -> Result<(), Error>
+    where
+        S: Stream<Item = reqwest::Result<Bytes>> + Send,
+    {
+        futures::pin_mut!(body);
+        while let Some(chunk) = body.try_next().await.map_err(Error::Remote)? {
+            std::io::stdout().write_all(&chunk).map_err(Error::File)?;
+        }
+        std::io::stdout().flush().map_err(Error::File)
+    }
+}
+
+/// Write a response body to a sink, returning its checksum.
+async fn write_body(
+    client: &Client,
+    response: reqwest::Response,
+    sink: &(impl OutputSink + ?Sized),
+    name: &str,
+) -> Result<Checksum, Error> {
+    let mut hasher = Sha256::new();
+    let mut size = 0;
+    let body = response
+        .bytes_stream()
+        .then(|chunk| async move {
+            if let Some(delay) = client.chunk_delay {
+                tokio::time::sleep(delay).await;
+            }
+            chunk
+        })
+        .map_ok(|chunk| {
+            hasher.update(&chunk);
+            size += u64::try_from(chunk.len()).expect("usize in u64");
+            chunk
+        });
+    sink.write_media(name, body).await?;
+    Ok(Checksum {
+        size,
+        sha256: format!("{:x}", hasher.finalize()),
+    })
+}
+
+/// Download media to a sink, streaming the body as it arrives rather than holding it in
+/// memory.
+pub async fn media(
+    client: &Client,
+    url: url::Url,
+    sink: &(impl OutputSink + ?Sized),
+    name: &str,
+) -> Result<Checksum, Error> {
+    let response = client.http.get(url).send().await.map_err(Error::Remote)?;
+    let response = check_response(response).await?;
+    write_body(client, response, sink, name).await
+}
+
+/// Download media to a sink, sending validators from a previous download if given.
+///
+/// Nothing is written until the server has responded with new content, so a
+/// `304 Not Modified` leaves any existing file untouched.
+pub async fn conditional_media(
+    client: &Client,
+    url: url::Url,
+    sink: &(impl OutputSink + ?Sized),
+    name: &str,
+    previous: Option<&Validators>,
+) -> Result<Fetched, Error> {
+    let mut request = client.http.get(url);
+    if let Some(previous) = previous {
+        if let Some(etag) = &previous.etag {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &previous.last_modified {
+            request = request.header(IF_MODIFIED_SINCE, last_modified);
+        }
+    }
+    let response = request.send().await.map_err(Error::Remote)?;
+    if response.status() == StatusCode::NOT_MODIFIED {
+        return Ok(Fetched::NotModified);
+    }
+    let response = check_response(response).await?;
+    let validators = Validators::from_headers(response.headers());
+    let checksum = write_body(client, response, sink, name).await?;
+    Ok(Fetched::Downloaded {
+        validators,
+        checksum,
+    })
+}
+
+/// Download a photo that wasn't found at `url`, trying each of `sizes` in turn until
+/// one is found. `on_retry` is called with each url before it is tried.
+///
+/// Returns the url the photo was found at, or `error` if no size was found.
+pub async fn fallback_size(
+    client: &Client,
+    url: &url::Url,
+    sizes: &[ImageSize],
+    sink: &(impl OutputSink + ?Sized),
+    name: &str,
+    error: Error,
+    mut on_retry: impl FnMut(&url::Url),
+) -> Result<(url::Url, Fetched), Error> {
+    for size in sizes {
+        let mut sized = url.clone();
+        size.apply(&mut sized);
+        on_retry(&sized);
+        match conditional_media(client, sized.clone(), sink, name, None).await {
+            Err(error) if error.status() == Some(StatusCode::NOT_FOUND) => continue,
+            result => return result.map(|fetched| (sized, fetched)),
+        }
+    }
+    Err(error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use pretty_assertions::assert_eq;
+    use std::collections::HashSet;
+    use std::sync::Arc;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    fn stripped(url: &str) -> String {
+        let mut url: url::Url = url.parse().unwrap();
+        strip_query(&mut url);
+        url.to_string()
+    }
+
+    #[test]
+    fn strip_query_removes_tracking_params() {
+        assert_eq!(
+            stripped("https://pbs.twimg.com/media/FgShiny.jpg?utm_source=feed&s=20"),
+            "https://pbs.twimg.com/media/FgShiny.jpg"
+        );
+    }
+
+    #[test]
+    fn strip_query_keeps_meaningful_params() {
+        assert_eq!(
+            stripped("https://pbs.twimg.com/media/FgShiny?format=jpg&utm_source=feed&name=orig"),
+            "https://pbs.twimg.com/media/FgShiny?format=jpg&name=orig"
+        );
+        assert_eq!(
+            stripped("https://pbs.twimg.com/media/FgShiny?format=jpg&name=orig"),
+            "https://pbs.twimg.com/media/FgShiny?format=jpg&name=orig"
+        );
+    }
+
+    #[test]
+    fn strip_query_without_query() {
+        assert_eq!(
+            stripped("https://pbs.twimg.com/media/FgShiny.jpg"),
+            "https://pbs.twimg.com/media/FgShiny.jpg"
+        );
+        assert_eq!(
+            stripped("https://pbs.twimg.com/media/FgShiny.jpg?"),
+            "https://pbs.twimg.com/media/FgShiny.jpg"
+        );
+    }
+
+    #[test]
+    fn numbered_filename_before_extension() {
+        assert_eq!(numbered_filename("a b.jpg", 1), "a b (1).jpg");
+        assert_eq!(
+            numbered_filename("magpie/2022-11/a.jpg", 2),
+            "magpie/2022-11/a (2).jpg"
+        );
+        assert_eq!(numbered_filename("noextension", 3), "noextension (3)");
+        assert_eq!(numbered_filename(".hidden", 1), ".hidden (1)");
+    }
+
+    fn taken_by<'a>(names: &'a [&str]) -> impl Fn(&str) -> bool + 'a {
+        let names: HashSet<&str> = names.iter().copied().collect();
+        move |name| names.contains(name)
+    }
+
+    #[test]
+    fn free_filename_is_kept_by_every_strategy() {
+        for on_collision in [
+            OnCollision::Skip,
+            OnCollision::Overwrite,
+            OnCollision::Rename,
+        ] {
+            assert_eq!(
+                resolve_collision("a.jpg", on_collision, taken_by(&["b.jpg"])),
+                Some("a.jpg".to_owned())
+            );
+        }
+    }
+
+    #[test]
+    fn skip_collision() {
+        assert_eq!(
+            resolve_collision("a.jpg", OnCollision::Skip, taken_by(&["a.jpg"])),
+            None
+        );
+    }
+
+    #[test]
+    fn overwrite_collision() {
+        assert_eq!(
+            resolve_collision("a.jpg", OnCollision::Overwrite, taken_by(&["a.jpg"])),
+            Some("a.jpg".to_owned())
+        );
+    }
+
+    #[test]
+    fn rename_collision_to_first_free_number() {
+        assert_eq!(
+            resolve_collision(
+                "a.jpg",
+                OnCollision::Rename,
+                taken_by(&["a.jpg", "a (1).jpg", "a (2).jpg"])
+            ),
+            Some("a (3).jpg".to_owned())
+        );
+    }
+
+    #[test]
+    fn rename_collision_reuses_earlier_rename() {
+        // "a (1).jpg" was written by an earlier run for the same media, so isn't taken.
+        assert_eq!(
+            resolve_collision(
+                "a.jpg",
+                OnCollision::Rename,
+                taken_by(&["a.jpg", "a (2).jpg"])
+            ),
+            Some("a (1).jpg".to_owned())
+        );
+    }
+
+    /// Serve photos over HTTP on a local port, responding to each request with the
+    /// status given for its `name` query parameter, or 404. Returns the base url and
+    /// the paths requested so far.
+    async fn serve_sizes(
+        sizes: &'static [(&'static str, u16)],
+    ) -> (url::Url, Arc<std::sync::Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!(
+            "http://{}/media/FgShiny.jpg",
+            listener.local_addr().unwrap()
+        );
+        let requested = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let log = requested.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut stream = BufReader::new(stream);
+                let mut request_line = String::new();
+                stream.read_line(&mut request_line).await.unwrap();
+                let mut header = String::new();
+                while stream.read_line(&mut header).await.unwrap() > 2 {
+                    header.clear();
+                }
+                let path = request_line.split(' ').nth(1).unwrap().to_owned();
+                let status = sizes
+                    .iter()
+                    .find(|(size, _)| path.ends_with(&format!("name={size}")))
+                    .map_or(404, |(_, status)| *status);
+                log.lock().unwrap().push(path);
+                let response = format!(
+                    "HTTP/1.1 {status} Status\r\ncontent-type: image/jpeg\r\ncontent-length: 5\r\nconnection: close\r\n\r\nshiny"
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        (base.parse().unwrap(), requested)
+    }
+
+    fn not_found() -> Error {
+        Error::Status {
+            status: StatusCode::NOT_FOUND,
+            body: String::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn fallback_size_after_orig_not_found() {
+        let (mut url, requested) = serve_sizes(&[("large", 200)]).await;
+        ImageSize::Orig.apply(&mut url);
+        let out_dir = tempfile::tempdir().unwrap();
+        let mut retried = Vec::new();
+
+        let (found, fetched) = fallback_size(
+            &client([], Redirects::default()),
+            &url,
+            ImageSize::Orig.fallbacks(),
+            out_dir.path(),
+            "FgShiny.jpg",
+            not_found(),
+            |url| retried.push(url.to_string()),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(found.query(), Some("name=large"));
+        assert_eq!(retried, vec![found.to_string()]);
+        assert_eq!(
+            *requested.lock().unwrap(),
+            vec!["/media/FgShiny.jpg?name=large"]
+        );
+        assert!(matches!(fetched, Fetched::Downloaded { checksum, .. } if checksum.size == 5));
+        assert_eq!(
+            std::fs::read(out_dir.path().join("FgShiny.jpg")).unwrap(),
+            b"shiny"
+        );
+    }
+
+    #[tokio::test]
+    async fn fallback_size_tries_each_smaller_size() {
+        let (mut url, requested) = serve_sizes(&[("medium", 200)]).await;
+        ImageSize::Orig.apply(&mut url);
+        let out_dir = tempfile::tempdir().unwrap();
+
+        let (found, _) = fallback_size(
+            &client([], Redirects::default()),
+            &url,
+            ImageSize::Orig.fallbacks(),
+            out_dir.path(),
+            "FgShiny.jpg",
+            not_found(),
+            |_| (),
+        )
         .await
-        .map_err(Error::Remote)?
-        .bytes()
+        .unwrap();
+
+        assert_eq!(found.query(), Some("name=medium"));
+        assert_eq!(
+            *requested.lock().unwrap(),
+            vec![
+                "/media/FgShiny.jpg?name=large",
+                "/media/FgShiny.jpg?name=medium"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn fallback_size_returns_original_error_if_nothing_found() {
+        let (mut url, _) = serve_sizes(&[]).await;
+        ImageSize::Orig.apply(&mut url);
+        let out_dir = tempfile::tempdir().unwrap();
+
+        let error = fallback_size(
+            &client([], Redirects::default()),
+            &url,
+            ImageSize::Orig.fallbacks(),
+            out_dir.path(),
+            "FgShiny.jpg",
+            Error::Status {
+                status: StatusCode::NOT_FOUND,
+                body: "orig".to_owned(),
+            },
+            |_| (),
+        )
         .await
-        .map_err(Error::Remote)?;
-    file.write_all(bytes).map_err(Error::File)?;
-    Ok(())
+        .unwrap_err();
+
+        assert!(matches!(error, Error::Status { body, .. } if body == "orig"));
+        assert!(!out_dir.path().join("FgShiny.jpg").exists());
+    }
+
+    #[tokio::test]
+    async fn media_is_streamed_to_sink() {
+        let (mut url, _) = serve_sizes(&[("orig", 200)]).await;
+        ImageSize::Orig.apply(&mut url);
+        let sink = fixtures::MemorySink::default();
+
+        let checksum = media(
+            &client([], Redirects::default()),
+            url,
+            &sink,
+            "magpie/FgShiny.jpg",
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            sink.written(),
+            [("magpie/FgShiny.jpg".to_owned(), b"shiny".to_vec())].into()
+        );
+        assert_eq!(checksum.size, 5);
+        assert_eq!(checksum.sha256, format!("{:x}", Sha256::digest(b"shiny")));
+    }
+
+    #[tokio::test]
+    async fn failed_media_writes_nothing_to_sink() {
+        let (url, _) = serve_sizes(&[]).await;
+        let sink = fixtures::MemorySink::default();
+
+        let error = media(&client([], Redirects::default()), url, &sink, "FgShiny.jpg")
+            .await
+            .unwrap_err();
+
+        assert_eq!(error.status(), Some(StatusCode::NOT_FOUND));
+        assert!(sink.written().is_empty());
+    }
 }
@@ -0,0 +1,224 @@
+//! Estimates of the API requests an archive run makes, to plan around rate limits.
+
+use crate::bot::{self, Page};
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+use twitter_v2::data::ReferencedTweetKind;
+use twitter_v2::id::NumericId;
+
+/// Length of Twitter's rate limit windows.
+const WINDOW: Duration = Duration::from_secs(15 * 60);
+
+/// Requests allowed per user in each window, for each endpoint a run uses.
+const LIKED_TWEETS_LIMIT: usize = 75;
+const USER_LOOKUP_LIMIT: usize = 900;
+const TWEET_LOOKUP_LIMIT: usize = 900;
+
+/// API requests an archive run of some pages of liked tweets would make.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Estimate {
+    /// Pages of liked tweets, each one request.
+    pub pages: usize,
+    /// Batches of up to 100 unique uncached authors of tweets with wanted media.
+    pub author_lookups: usize,
+    /// Pages with quoted tweets to look up, if quoted media is included.
+    pub quoted_lookups: usize,
+}
+
+impl Estimate {
+    /// Estimate the requests to process already fetched pages. Author lookups are
+    /// skipped if authors are named by id, and for authors already cached.
+    pub fn of_pages(
+        pages: &[Page],
+        cached_authors: &HashSet<NumericId>,
+        author_ids_as_names: bool,
+        include_quoted: bool,
+        include_videos: bool,
+    ) -> Self {
+        let mut authors: HashSet<NumericId> = HashSet::new();
+        let mut quoted_lookups = 0;
+        for page in pages {
+            let includes_media: HashMap<_, _> = page
+                .includes()
+                .and_then(|includes| includes.media.as_ref())
+                .into_iter()
+                .flatten()
+                .map(|media| (media.media_key.clone(), media.clone()))
+                .collect();
+            let tweets = page.data().into_iter().flatten();
+            let mut has_quoted = false;
+            for tweet in tweets {
+                if bot::has_wanted_media(tweet, &includes_media, include_videos) {
+                    authors.extend(
+                        tweet
+                            .author_id
+                            .filter(|author_id| !cached_authors.contains(author_id)),
+                    );
+                }
+                has_quoted |= tweet
+                    .referenced_tweets
+                    .iter()
+                    .flatten()
+                    .any(|referenced| referenced.kind == ReferencedTweetKind::Quoted);
+            }
+            if include_quoted && has_quoted {
+                quoted_lookups += 1;
+            }
+        }
+        Self {
+            pages: pages.len(),
+            author_lookups: if author_ids_as_names {
+                0
+            } else {
+                authors.len().div_ceil(bot::MAX_USER_LOOKUP)
+            },
+            quoted_lookups,
+        }
+    }
+
+    /// Total requests, including the one looking up the logged in user.
+    pub fn requests(&self) -> usize {
+        1 + self.pages + self.author_lookups + self.quoted_lookups
+    }
+
+    /// Rough wall clock time spent waiting for rate limits to reset. Pages are fetched
+    /// before authors and quoted tweets are looked up, which happens concurrently.
+    pub fn rate_limit_wait(&self) -> Duration {
+        let windows_waited = |requests: usize, limit: usize| {
+            u32::try_from(requests.saturating_sub(1) / limit).unwrap_or(u32::MAX)
+        };
+        let pages = windows_waited(self.pages, LIKED_TWEETS_LIMIT);
+        let lookups = windows_waited(self.author_lookups, USER_LOOKUP_LIMIT)
+            .max(windows_waited(self.quoted_lookups, TWEET_LOOKUP_LIMIT));
+        WINDOW * pages.saturating_add(lookups)
+    }
+
+    pub fn log(&self) {
+        log::info!("Estimated {} API requests:", self.requests());
+        log::info!("  {} pages of liked tweets", self.pages);
+        log::info!("  {} batched author lookups", self.author_lookups);
+        if self.quoted_lookups > 0 {
+            log::info!("  {} quoted tweet lookups", self.quoted_lookups);
+        }
+        log::info!(
+            "Estimated {} waiting for rate limits, plus download time",
+            indicatif::HumanDuration(self.rate_limit_wait())
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use pretty_assertions::assert_eq;
+
+    fn tweet_by(id: &str, author_id: &str, media_keys: &[&str]) -> serde_json::Value {
+        let mut tweet = fixtures::tweet(id, "2022-11-05T12:00:00Z", media_keys);
+        tweet["author_id"] = author_id.into();
+        tweet
+    }
+
+    fn page() -> Page {
+        let mut quoting = tweet_by("4", "4", &["3_4"]);
+        quoting["referenced_tweets"] = serde_json::json!([{"type": "quoted", "id": "9"}]);
+        fixtures::page(serde_json::json!({
+            "data": [
+                tweet_by("1", "1", &["3_1"]),
+                tweet_by("2", "2", &["3_2"]),
+                tweet_by("3", "3", &["7_3"]),
+                quoting,
+            ],
+            "includes": {
+                "media": [
+                    fixtures::photo("3_1", "https://pbs.twimg.com/media/One.jpg"),
+                    fixtures::photo("3_2", "https://pbs.twimg.com/media/Two.jpg"),
+                    fixtures::video("7_3", "https://video.twimg.com/Three.mp4"),
+                    fixtures::photo("3_4", "https://pbs.twimg.com/media/Four.jpg"),
+                ],
+            },
+        }))
+    }
+
+    fn ids(ids: &[u64]) -> HashSet<NumericId> {
+        ids.iter().copied().map(NumericId::new).collect()
+    }
+
+    #[test]
+    fn of_pages_counts_authors_of_photos() {
+        assert_eq!(
+            Estimate::of_pages(&[page(), page()], &ids(&[]), false, false, false),
+            Estimate {
+                pages: 2,
+                author_lookups: 1,
+                quoted_lookups: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn of_pages_skips_cached_authors() {
+        let cached = ids(&[1, 2, 4]);
+        assert_eq!(
+            Estimate::of_pages(&[page()], &cached, false, false, false).author_lookups,
+            0
+        );
+        assert_eq!(
+            Estimate::of_pages(&[page()], &cached, false, false, true).author_lookups,
+            1
+        );
+    }
+
+    #[test]
+    fn of_pages_batches_authors() {
+        let tweets: Vec<_> = (1..=bot::MAX_USER_LOOKUP + 1)
+            .map(|id| tweet_by(&id.to_string(), &id.to_string(), &["3_1"]))
+            .collect();
+        let page = fixtures::page(serde_json::json!({
+            "data": tweets,
+            "includes": {
+                "media": [fixtures::photo("3_1", "https://pbs.twimg.com/media/One.jpg")],
+            },
+        }));
+        assert_eq!(
+            Estimate::of_pages(&[page], &ids(&[]), false, false, false).author_lookups,
+            2
+        );
+    }
+
+    #[test]
+    fn of_pages_without_author_lookups() {
+        assert_eq!(
+            Estimate::of_pages(&[page()], &ids(&[]), true, false, false).author_lookups,
+            0
+        );
+    }
+
+    #[test]
+    fn of_pages_counts_quoted_lookups_if_included() {
+        assert_eq!(
+            Estimate::of_pages(&[page(), page()], &ids(&[]), false, true, false).quoted_lookups,
+            2
+        );
+    }
+
+    #[test]
+    fn rate_limit_wait_within_limits() {
+        let estimate = Estimate {
+            pages: LIKED_TWEETS_LIMIT,
+            author_lookups: USER_LOOKUP_LIMIT,
+            quoted_lookups: TWEET_LOOKUP_LIMIT,
+        };
+        assert_eq!(estimate.rate_limit_wait(), Duration::ZERO);
+    }
+
+    #[test]
+    fn rate_limit_wait_adds_pages_to_longest_lookups() {
+        let estimate = Estimate {
+            pages: 2 * LIKED_TWEETS_LIMIT + 1,
+            author_lookups: USER_LOOKUP_LIMIT + 1,
+            quoted_lookups: 1,
+        };
+        assert_eq!(estimate.rate_limit_wait(), 3 * WINDOW);
+    }
+}
@@ -0,0 +1,102 @@
+//! Newline-delimited JSON export of media as it is found.
+
+use crate::bot::ImageRef;
+use crate::naming::NameBuilder;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::Path;
+use twitter_v2::id::NumericId;
+
+/// A JSON record for one piece of media.
+#[derive(Debug, Serialize)]
+struct Record<'a> {
+    filename: String,
+    url: &'a str,
+    tweet_id: NumericId,
+    username: &'a str,
+    #[serde(with = "time::serde::rfc3339")]
+    created_at: time::OffsetDateTime,
+    text: &'a str,
+    media_key: &'a str,
+    media_index: usize,
+}
+
+/// Writes one JSON object per line, flushing after each so that records written before a
+/// crash are kept.
+pub struct JsonlWriter {
+    out: Box<dyn Write + Send>,
+    names: NameBuilder,
+}
+
+impl JsonlWriter {
+    /// Write to the file at `path`, or to stdout if it is `-`. Filenames are recorded as
+    /// named by `names`.
+    pub fn create(path: &Path, names: NameBuilder) -> std::io::Result<Self> {
+        let out: Box<dyn Write + Send> = if path == Path::new("-") {
+            Box::new(std::io::stdout())
+        } else {
+            Box::new(std::fs::File::create(path)?)
+        };
+        Ok(Self { out, names })
+    }
+
+    pub fn write(&mut self, image_ref: &ImageRef) -> std::io::Result<()> {
+        let record = Record {
+            filename: self.names.base_filename(image_ref),
+            url: image_ref.url.as_str(),
+            tweet_id: image_ref.tweet.id,
+            username: &image_ref.tweet.username,
+            created_at: image_ref.tweet.created_at,
+            text: &image_ref.tweet.text,
+            media_key: &image_ref.media_key,
+            media_index: image_ref.media_index,
+        };
+        serde_json::to_writer(&mut self.out, &record)?;
+        self.out.write_all(b"\n")?;
+        self.out.flush()
+    }
+}
+
+/// Write media found by a run to `path`, one serialized [`ImageRef`] per line, to be
+/// downloaded later with [`read_image_refs`].
+pub fn write_image_refs(path: &Path, image_refs: &[ImageRef]) -> std::io::Result<()> {
+    let mut out = std::io::BufWriter::new(std::fs::File::create(path)?);
+    for image_ref in image_refs {
+        serde_json::to_writer(&mut out, image_ref)?;
+        out.write_all(b"\n")?;
+    }
+    out.flush()
+}
+
+/// Read media written by [`write_image_refs`]. Blank lines are skipped.
+pub fn read_image_refs(path: &Path) -> std::io::Result<Vec<ImageRef>> {
+    std::fs::read_to_string(path)?
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| serde_json::from_str(line).map_err(std::io::Error::from))
+        .collect()
+}
+
+/// Write a table of author usernames by id, as CSV if `path` ends in `.csv`, or as a
+/// JSON object otherwise.
+pub fn write_usernames(
+    path: &Path,
+    usernames: &BTreeMap<NumericId, String>,
+) -> std::io::Result<()> {
+    let contents = if path.extension().is_some_and(|extension| extension == "csv") {
+        // Usernames are only letters, digits and underscores, so need no quoting.
+        let mut contents = String::from("id,username\n");
+        for (id, username) in usernames {
+            contents.push_str(&format!("{id},{username}\n"));
+        }
+        contents
+    } else {
+        let usernames: BTreeMap<String, &str> = usernames
+            .iter()
+            .map(|(id, username)| (id.to_string(), username.as_str()))
+            .collect();
+        serde_json::to_string_pretty(&usernames)?
+    };
+    std::fs::write(path, contents)
+}
@@ -0,0 +1,56 @@
+//! Filters applied to the full list of image references after processing.
+
+use crate::bot::ImageRef;
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use twitter_v2::id::NumericId;
+
+/// Keep at most `max` images per author, preferring the most recent tweets.
+///
+/// Tweets with the same timestamp are ordered by tweet id, so the newer tweet wins.
+/// Images from the same tweet keep their original order. Returns the kept images,
+/// and the number dropped.
+pub fn cap_per_author(mut image_refs: Vec<ImageRef>, max: usize) -> (Vec<ImageRef>, usize) {
+    image_refs.sort_by_key(|image_ref| Reverse((image_ref.tweet.created_at, image_ref.tweet.id)));
+    let total = image_refs.len();
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    image_refs.retain(|image_ref| {
+        let count = counts.entry(image_ref.tweet.username.clone()).or_default();
+        *count += 1;
+        *count <= max
+    });
+    let dropped = total - image_refs.len();
+    (image_refs, dropped)
+}
+
+/// Shortest run of digits in a filename taken to be a tweet id. Shorter runs are more
+/// likely parts of dates, times or counters.
+const MIN_TWEET_ID_DIGITS: usize = 10;
+
+/// Tweet ids that might appear in a filename, as runs of digits, whatever naming
+/// convention the file was saved with.
+fn tweet_ids_in_filename(filename: &str) -> impl Iterator<Item = NumericId> + '_ {
+    filename
+        .split(|c: char| !c.is_ascii_digit())
+        .filter(|digits| digits.len() >= MIN_TWEET_ID_DIGITS)
+        .filter_map(|digits| digits.parse().ok())
+}
+
+/// Tweet ids found in the names of files anywhere under a directory, such as an archive
+/// saved by another tool.
+pub fn tweet_ids_in_dir(dir: &Path) -> std::io::Result<HashSet<NumericId>> {
+    let mut tweet_ids = HashSet::new();
+    let mut dirs = vec![dir.to_owned()];
+    while let Some(dir) = dirs.pop() {
+        for entry in std::fs::read_dir(&dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                dirs.push(entry.path());
+            } else {
+                tweet_ids.extend(tweet_ids_in_filename(&entry.file_name().to_string_lossy()));
+            }
+        }
+    }
+    Ok(tweet_ids)
+}
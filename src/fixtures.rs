@@ -0,0 +1,126 @@
+//! Tweets and media shared by unit tests.
+
+use crate::bot::{Bot, ImageRef, Page, TweetRef};
+use crate::download::{self, OutputSink};
+use bytes::Bytes;
+use futures::{Stream, TryStreamExt};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use twitter_v2::authorization::Oauth2Token;
+use twitter_v2::data::MediaType;
+use twitter_v2::id::NumericId;
+
+/// 2022-11-05T12:34:56Z.
+pub fn created_at() -> time::OffsetDateTime {
+    time::OffsetDateTime::from_unix_timestamp(1_667_651_696).expect("valid timestamp")
+}
+
+/// A photo attached to a tweet by `magpie`, the only media of the tweet.
+pub fn image_ref() -> ImageRef {
+    ImageRef {
+        tweet: TweetRef {
+            created_at: created_at(),
+            username: "magpie".to_owned(),
+            id: NumericId::new(1_588_888_888_888_888_888),
+            text: "A shiny thing".to_owned(),
+            expanded_urls: Default::default(),
+            media_count: 1,
+        },
+        media_key: "3_1588888888888888881".to_owned(),
+        media_index: 0,
+        kind: MediaType::Photo,
+        internal_filename: "FgShiny.jpg".to_owned(),
+        url: "https://pbs.twimg.com/media/FgShiny.jpg"
+            .parse()
+            .expect("valid url"),
+        alt_text: None,
+    }
+}
+
+/// A login that is never used, as requests made with it fail.
+pub fn token() -> Oauth2Token {
+    serde_json::from_value(serde_json::json!({
+        "access_token": "unused",
+        "refresh_token": null,
+        "expires": "2100-01-01T00:00:00Z",
+        "scopes": [],
+    }))
+    .expect("valid token")
+}
+
+/// An error response from the Twitter API with the status given.
+pub fn api_error(status: reqwest::StatusCode) -> twitter_v2::Error {
+    twitter_v2::Error::Api(twitter_v2::api_result::ApiError {
+        status,
+        ..Default::default()
+    })
+}
+
+/// A bot whose login is never used, for processing tweets without API requests.
+pub fn bot() -> Bot {
+    Bot::new(token())
+}
+
+/// A page of tweets, deserialized from JSON as the API returns it.
+pub fn page(page: serde_json::Value) -> Page {
+    serde_json::from_value(page).expect("valid page")
+}
+
+/// A tweet by author `1`, created at `created_at` as RFC 3339, with the media attached.
+pub fn tweet(id: &str, created_at: &str, media_keys: &[&str]) -> serde_json::Value {
+    let mut tweet = serde_json::json!({
+        "id": id,
+        "text": "A shiny thing",
+        "author_id": "1",
+        "created_at": created_at,
+    });
+    if !media_keys.is_empty() {
+        tweet["attachments"] = serde_json::json!({ "media_keys": media_keys });
+    }
+    tweet
+}
+
+/// A photo with the url given.
+pub fn photo(media_key: &str, url: &str) -> serde_json::Value {
+    serde_json::json!({
+        "media_key": media_key,
+        "type": "photo",
+        "url": url,
+    })
+}
+
+/// A video with a single MP4 variant at the url given.
+pub fn video(media_key: &str, url: &str) -> serde_json::Value {
+    serde_json::json!({
+        "media_key": media_key,
+        "type": "video",
+        "variants": [
+            { "bit_rate": 832000, "content_type": "video/mp4", "url": url },
+        ],
+    })
+}
+
+/// Media written to memory by name, for testing downloads without touching the disk.
+#[derive(Debug, Default)]
+pub struct MemorySink(Mutex<BTreeMap<String, Vec<u8>>>);
+
+impl MemorySink {
+    /// Everything written so far, by name.
+    pub fn written(&self) -> BTreeMap<String, Vec<u8>> {
+        self.0.lock().expect("sink lock poisoned").clone()
+    }
+}
+
+impl OutputSink for MemorySink {
+    async fn write_media<S>(&self, name: &str, body: S) -> Result<(), download::Error>
+    where
+        S: Stream<Item = reqwest::Result<Bytes>> + Send,
+    {
+        let chunks: Vec<Bytes> = body.try_collect().await.map_err(download::Error::Remote)?;
+        self.0
+            .lock()
+            .expect("sink lock poisoned")
+            .insert(name.to_owned(), chunks.concat());
+        Ok(())
+    }
+}
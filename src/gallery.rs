@@ -0,0 +1,144 @@
+//! A browsable HTML page of downloaded media.
+
+use std::fmt::Write;
+use time::format_description::well_known::Rfc3339;
+
+/// Name of the gallery page, stored in the root of the output directory.
+pub const FILENAME: &str = "index.html";
+
+/// A downloaded file to show in the gallery.
+#[derive(Debug, Clone)]
+pub struct Item {
+    /// Filename relative to the output directory.
+    pub filename: String,
+    pub username: String,
+    pub permalink: String,
+    pub created_at: time::OffsetDateTime,
+}
+
+/// Escape text for use in HTML content and attribute values.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Percent encode a relative path for use as a link, keeping `/` separators.
+fn encode_path(path: &str) -> String {
+    let mut encoded = String::from("./");
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                encoded.push(byte as char)
+            }
+            byte => write!(encoded, "%{byte:02X}").expect("write to string"),
+        }
+    }
+    encoded
+}
+
+/// Extensions of video files, which are shown with a player rather than as an image.
+const VIDEO_EXTENSIONS: [&str; 2] = ["mp4", "m3u8"];
+
+/// Whether a file is a video, by its extension.
+fn is_video(filename: &str) -> bool {
+    filename
+        .rsplit_once('.')
+        .is_some_and(|(_, extension)| VIDEO_EXTENSIONS.contains(&extension.to_lowercase().as_str()))
+}
+
+/// Render the gallery page, with the most recent tweets first.
+pub fn render(items: &[Item]) -> String {
+    let mut items: Vec<&Item> = items.iter().collect();
+    items.sort_by(|a, b| {
+        b.created_at
+            .cmp(&a.created_at)
+            .then_with(|| a.filename.cmp(&b.filename))
+    });
+
+    let mut figures = String::new();
+    for item in items {
+        let href = escape(&encode_path(&item.filename));
+        let created_at = item.created_at.format(&Rfc3339).expect("format created at");
+        let media = if is_video(&item.filename) {
+            format!(r#"<video src="{href}" controls preload="metadata"></video>"#)
+        } else {
+            format!(r#"<a href="{href}"><img src="{href}" loading="lazy" alt=""></a>"#)
+        };
+        writeln!(
+            figures,
+            r#"<figure>{media}<figcaption><a href="{permalink}">@{username}</a> <time datetime="{created_at}">{date}</time></figcaption></figure>"#,
+            permalink = escape(&item.permalink),
+            username = escape(&item.username),
+            date = item.created_at.date(),
+        )
+        .expect("write to string");
+    }
+
+    format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>magpie</title>
+<style>
+body {{ font-family: sans-serif; margin: 1em; }}
+main {{ display: flex; flex-wrap: wrap; gap: 1em; }}
+figure {{ margin: 0; width: 200px; }}
+img, video {{ width: 200px; height: 200px; object-fit: cover; }}
+figcaption {{ font-size: small; }}
+</style>
+</head>
+<body>
+<main>
+{figures}</main>
+</body>
+</html>
+"#
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    fn item(filename: &str) -> Item {
+        Item {
+            filename: filename.to_owned(),
+            username: "magpie".to_owned(),
+            permalink: "https://twitter.com/magpie/status/1".to_owned(),
+            created_at: fixtures::created_at(),
+        }
+    }
+
+    #[test]
+    fn photos_are_images() {
+        let rendered = render(&[item("a b.jpg")]);
+        assert!(rendered.contains(
+            r#"<a href="./a%20b.jpg"><img src="./a%20b.jpg" loading="lazy" alt=""></a>"#
+        ));
+        assert!(!rendered.contains("<video"));
+    }
+
+    #[test]
+    fn videos_have_a_player() {
+        let rendered = render(&[item("clip.mp4"), item("stream.M3U8")]);
+        assert!(
+            rendered.contains(r#"<video src="./clip.mp4" controls preload="metadata"></video>"#)
+        );
+        assert!(
+            rendered.contains(r#"<video src="./stream.M3U8" controls preload="metadata"></video>"#)
+        );
+        assert!(!rendered.contains("<img"));
+    }
+}
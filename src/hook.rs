@@ -0,0 +1,66 @@
+//! User commands run on each downloaded file.
+
+use crate::bot::ImageRef;
+use std::path::Path;
+use time::format_description::well_known::Rfc3339;
+use tokio::sync::Semaphore;
+
+/// A shell command run after each successful download, with limited concurrency.
+///
+/// The file path is appended to the command as its last argument. Tweet metadata is
+/// passed in `MAGPIE_*` environment variables.
+pub struct Hook {
+    command: String,
+    permits: Semaphore,
+}
+
+impl Hook {
+    pub fn new(command: String, concurrency: usize) -> Self {
+        Self {
+            command,
+            permits: Semaphore::new(concurrency),
+        }
+    }
+
+    /// Run the hook for a downloaded file, returning whether it exited successfully.
+    pub async fn run(&self, path: &Path, image_ref: &ImageRef) -> std::io::Result<bool> {
+        let _permit = self.permits.acquire().await.expect("hook semaphore closed");
+        let mut command = shell_command(&self.command);
+        command
+            .arg(path)
+            .env("MAGPIE_PATH", path)
+            .env("MAGPIE_URL", image_ref.url.as_str())
+            .env("MAGPIE_MEDIA_KEY", &image_ref.media_key)
+            .env("MAGPIE_TWEET_ID", image_ref.tweet.id.to_string())
+            .env("MAGPIE_USERNAME", &image_ref.tweet.username)
+            .env(
+                "MAGPIE_CREATED_AT",
+                image_ref
+                    .tweet
+                    .created_at
+                    .format(&Rfc3339)
+                    .expect("format created at"),
+            )
+            .stdin(std::process::Stdio::null());
+        let status = command.status().await?;
+        if !status.success() {
+            log::warn!("Hook for '{}' exited with {}", path.display(), status);
+        }
+        Ok(status.success())
+    }
+}
+
+#[cfg(unix)]
+fn shell_command(command: &str) -> tokio::process::Command {
+    let mut shell = tokio::process::Command::new("sh");
+    // "$@" expands to the arguments following the script name, here the file path.
+    shell.arg("-c").arg(format!("{command} \"$@\"")).arg("sh");
+    shell
+}
+
+#[cfg(not(unix))]
+fn shell_command(command: &str) -> tokio::process::Command {
+    let mut shell = tokio::process::Command::new("cmd");
+    shell.arg("/C").arg(command);
+    shell
+}
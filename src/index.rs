@@ -0,0 +1,232 @@
+//! Persistent record of media downloaded into an output directory, keyed by media key.
+
+use crate::download::Validators;
+use crate::gallery;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+/// Name of the index file, stored in the root of the output directory.
+pub const FILENAME: &str = ".magpie-index.json";
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Failed to read index '{}'", path.display())]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("Failed to write index '{}'", path.display())]
+    Write {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("Index '{}' is malformed", path.display())]
+    Malformed {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Entry {
+    /// Filename the media was written to, relative to the output directory.
+    pub filename: String,
+    #[serde(flatten)]
+    pub validators: Validators,
+    /// The tweet the media is from, for the gallery. Missing for media indexed by older
+    /// versions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tweet: Option<Tweet>,
+}
+
+/// The tweet media was downloaded from.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Tweet {
+    pub username: String,
+    pub permalink: String,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created_at: time::OffsetDateTime,
+}
+
+/// What to do with a download whose content is already in the archive under another name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Dedupe {
+    /// Delete the new file, keeping only the existing copy.
+    Skip,
+    /// Replace the new file with a hard link to the existing copy.
+    Link,
+}
+
+impl Dedupe {
+    /// Replace the file at `path` with the existing copy at `canonical`.
+    pub fn apply(self, canonical: &Path, path: &Path) -> std::io::Result<()> {
+        std::fs::remove_file(path)?;
+        match self {
+            Self::Skip => Ok(()),
+            Self::Link => std::fs::hard_link(canonical, path),
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Index {
+    entries: BTreeMap<String, Entry>,
+    /// Filename first used for each sha256 digest of file content.
+    #[serde(default)]
+    hashes: BTreeMap<String, String>,
+    /// Usernames of authors with media in the archive.
+    #[serde(default)]
+    authors: BTreeSet<String>,
+}
+
+impl Index {
+    pub fn path(out_dir: &Path) -> PathBuf {
+        out_dir.join(FILENAME)
+    }
+
+    /// Load the index for an output directory, or an empty index if none exists yet.
+    pub fn load(out_dir: &Path) -> Result<Self> {
+        let path = Self::path(out_dir);
+        let contents = match std::fs::read(&path) {
+            Ok(contents) => contents,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(Self::default())
+            }
+            Err(source) => return Err(Error::Read { path, source }),
+        };
+        serde_json::from_slice(&contents).map_err(|source| Error::Malformed { path, source })
+    }
+
+    /// Write the index, replacing any previous version atomically.
+    pub fn save(&self, out_dir: &Path) -> Result<()> {
+        let path = Self::path(out_dir);
+        let contents = serde_json::to_vec_pretty(self).expect("index is serializable");
+        let partial = path.with_extension("json.part");
+        std::fs::write(&partial, contents)
+            .and_then(|_| std::fs::rename(&partial, &path))
+            .map_err(|source| Error::Write { path, source })
+    }
+
+    pub fn get(&self, media_key: &str) -> Option<&Entry> {
+        self.entries.get(media_key)
+    }
+
+    pub fn insert(&mut self, media_key: String, entry: Entry) {
+        self.entries.insert(media_key, entry);
+    }
+
+    /// Filename of content previously stored with this sha256 digest.
+    pub fn canonical_filename(&self, sha256: &str) -> Option<&str> {
+        self.hashes.get(sha256).map(String::as_str)
+    }
+
+    /// Record a filename as the canonical copy of some content, unless one already exists.
+    pub fn insert_hash(&mut self, sha256: String, filename: String) {
+        self.hashes.entry(sha256).or_insert(filename);
+    }
+
+    /// Whether media by this author has been archived before.
+    pub fn has_author(&self, username: &str) -> bool {
+        self.authors.contains(username)
+    }
+
+    pub fn insert_author(&mut self, username: String) {
+        self.authors.insert(username);
+    }
+
+    /// Gallery items for all indexed media still in `out_dir`, from this run and earlier
+    /// ones. Media indexed without its tweet is left out.
+    pub fn gallery_items(&self, out_dir: &Path) -> Vec<gallery::Item> {
+        let mut items: BTreeMap<&str, gallery::Item> = BTreeMap::new();
+        for entry in self.entries.values() {
+            let Some(tweet) = &entry.tweet else {
+                continue;
+            };
+            if items.contains_key(entry.filename.as_str())
+                || !out_dir.join(&entry.filename).exists()
+            {
+                continue;
+            }
+            items.insert(
+                &entry.filename,
+                gallery::Item {
+                    filename: entry.filename.clone(),
+                    username: tweet.username.clone(),
+                    permalink: tweet.permalink.clone(),
+                    created_at: tweet.created_at,
+                },
+            );
+        }
+        items.into_values().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use pretty_assertions::assert_eq;
+
+    fn entry(filename: &str, tweet: Option<Tweet>) -> Entry {
+        Entry {
+            filename: filename.to_owned(),
+            tweet,
+            ..Default::default()
+        }
+    }
+
+    fn tweet(username: &str) -> Tweet {
+        Tweet {
+            username: username.to_owned(),
+            permalink: format!("https://twitter.com/{username}/status/1"),
+            created_at: fixtures::created_at(),
+        }
+    }
+
+    #[test]
+    fn gallery_includes_media_from_earlier_runs() {
+        let out_dir = tempfile::tempdir().unwrap();
+        for filename in ["earlier.jpg", "latest.jpg", "untitled.jpg"] {
+            std::fs::write(out_dir.path().join(filename), b"photo").unwrap();
+        }
+        let mut index = Index::default();
+        index.insert(
+            "3_1".to_owned(),
+            entry("earlier.jpg", Some(tweet("magpie"))),
+        );
+        index.save(out_dir.path()).unwrap();
+
+        // A later run loads the index, and downloads more media.
+        let mut index = Index::load(out_dir.path()).unwrap();
+        index.insert(
+            "3_2".to_owned(),
+            entry("latest.jpg", Some(tweet("jackdaw"))),
+        );
+        index.insert("3_3".to_owned(), entry("untitled.jpg", None));
+        index.insert("3_4".to_owned(), entry("deleted.jpg", Some(tweet("rook"))));
+
+        let items = index.gallery_items(out_dir.path());
+        let shown: Vec<(&str, &str)> = items
+            .iter()
+            .map(|item| (item.filename.as_str(), item.username.as_str()))
+            .collect();
+        assert_eq!(
+            shown,
+            vec![("earlier.jpg", "magpie"), ("latest.jpg", "jackdaw")]
+        );
+    }
+
+    #[test]
+    fn gallery_shows_deduplicated_file_once() {
+        let out_dir = tempfile::tempdir().unwrap();
+        std::fs::write(out_dir.path().join("a.jpg"), b"photo").unwrap();
+        let mut index = Index::default();
+        index.insert("3_1".to_owned(), entry("a.jpg", Some(tweet("magpie"))));
+        index.insert("3_2".to_owned(), entry("a.jpg", Some(tweet("jackdaw"))));
+        assert_eq!(index.gallery_items(out_dir.path()).len(), 1);
+    }
+}
@@ -1,3 +1,28 @@
+pub mod api;
 pub mod auth;
 pub mod bot;
+pub mod bucket;
+pub mod caption;
+pub mod clock;
+pub mod concurrency;
+pub mod controls;
 pub mod download;
+pub mod estimate;
+pub mod export;
+pub mod filter;
+#[cfg(test)]
+mod fixtures;
+pub mod gallery;
+pub mod hook;
+pub mod index;
+pub mod lock;
+pub mod manifest;
+pub mod marker;
+pub mod metrics;
+pub mod naming;
+pub mod permalink;
+pub mod png;
+pub mod progress;
+pub mod reencode;
+pub mod summary;
+pub mod video;
@@ -0,0 +1,227 @@
+//! Lock file preventing concurrent runs from writing to the same output directory.
+//!
+//! Runs take an advisory lock on the file, which the operating system releases when the
+//! run exits or crashes, so a lock left by a crashed run never blocks a later one. The file
+//! also records the process id and start time of the run holding it, to explain who holds
+//! the lock, and to guess whether it is stale when the lock can't be trusted, such as a
+//! run hung for a day or one on another host sharing the directory.
+
+use crate::clock::{Clock, SystemClock};
+use std::fs::{File, TryLockError};
+use std::io::{Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use thiserror::Error;
+
+/// Name of the lock file, stored in the root of the output directory.
+pub const FILENAME: &str = ".magpie.lock";
+
+/// Locks older than this are assumed to be left over from a hung run.
+const STALE_AFTER: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error(
+        "Output directory is locked by another run (process {pid}). {}",
+        if *stale {
+            format!("The lock looks stale, so if no other run is active, use --force to replace '{}'", path.display())
+        } else {
+            "Wait for it to finish, as --force only replaces stale locks".to_owned()
+        }
+    )]
+    Held {
+        path: PathBuf,
+        pid: u32,
+        stale: bool,
+    },
+    #[error("Failed to access lock file '{}'", path.display())]
+    File {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Holds the lock on an output directory until dropped.
+#[derive(Debug)]
+pub struct Lock {
+    /// Keeps the advisory lock, which is released when the file is closed.
+    _file: File,
+}
+
+impl Lock {
+    /// Lock an output directory. If `force` is set, a stale lock held by another run is
+    /// replaced.
+    pub fn acquire(out_dir: &Path, force: bool) -> Result<Self> {
+        Self::acquire_with_clock(out_dir, force, &SystemClock)
+    }
+
+    /// Lock an output directory, reading the time the lock is taken, and the age of any
+    /// lock held by another run, from `clock`.
+    pub fn acquire_with_clock(out_dir: &Path, force: bool, clock: &dyn Clock) -> Result<Self> {
+        let path = out_dir.join(FILENAME);
+        let file_error = |source| Error::File {
+            path: path.clone(),
+            source,
+        };
+        let now = unix_secs(clock);
+        let mut file = match try_lock(&path).map_err(file_error)? {
+            Ok(file) => file,
+            Err(held) => {
+                let (pid, created) = parse(&held);
+                let stale = is_stale(pid, created, now);
+                if !(force && stale) {
+                    return Err(Error::Held {
+                        path,
+                        pid: pid.unwrap_or_default(),
+                        stale,
+                    });
+                }
+                // The other run keeps its lock on the removed file, so a new file can be
+                // locked in its place.
+                log::warn!("Replacing stale lock '{}'", path.display());
+                std::fs::remove_file(&path).map_err(file_error)?;
+                try_lock(&path)
+                    .map_err(file_error)?
+                    .map_err(|held| Error::Held {
+                        path: path.clone(),
+                        pid: parse(&held).0.unwrap_or_default(),
+                        stale: false,
+                    })?
+            }
+        };
+        file.set_len(0).map_err(file_error)?;
+        writeln!(file, "{}\n{}", std::process::id(), now).map_err(file_error)?;
+        Ok(Self { _file: file })
+    }
+}
+
+/// Open and lock the lock file, creating it if needed. If another run holds the lock,
+/// returns the file's contents instead.
+fn try_lock(path: &Path) -> std::io::Result<std::result::Result<File, String>> {
+    let mut file = File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+    match file.try_lock() {
+        Ok(()) => Ok(Ok(file)),
+        Err(TryLockError::WouldBlock) => {
+            let mut contents = String::new();
+            file.rewind()?;
+            file.read_to_string(&mut contents)?;
+            Ok(Err(contents))
+        }
+        Err(TryLockError::Error(error)) => Err(error),
+    }
+}
+
+/// Parse the process id and creation time, in seconds since the epoch, from a lock file.
+fn parse(contents: &str) -> (Option<u32>, Option<u64>) {
+    let mut lines = contents.lines();
+    let pid = lines.next().and_then(|line| line.trim().parse().ok());
+    let created = lines.next().and_then(|line| line.trim().parse().ok());
+    (pid, created)
+}
+
+/// The current time, in seconds since the epoch.
+fn unix_secs(clock: &dyn Clock) -> u64 {
+    u64::try_from(clock.now().unix_timestamp()).expect("time after epoch")
+}
+
+/// Guess whether a lock is held by a run that is no longer making progress: either it is
+/// very old, or (where `/proc` is available) its process isn't running on this host.
+fn is_stale(pid: Option<u32>, created: Option<u64>, now: u64) -> bool {
+    let too_old = match created {
+        Some(created) => now.saturating_sub(created) > STALE_AFTER.as_secs(),
+        None => true,
+    };
+    let proc = Path::new("/proc");
+    let not_running = match pid {
+        Some(pid) => proc.is_dir() && !proc.join(pid.to_string()).exists(),
+        None => true,
+    };
+    too_old || not_running
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::FixedClock;
+    use pretty_assertions::assert_eq;
+
+    fn at(unix_secs: i64) -> FixedClock {
+        FixedClock(time::OffsetDateTime::from_unix_timestamp(unix_secs).unwrap())
+    }
+
+    /// Whether a second run, at `later_secs` after the lock was taken, sees it as stale.
+    fn held_stale_after(later_secs: i64) -> bool {
+        let out_dir = tempfile::tempdir().unwrap();
+        let _lock = Lock::acquire_with_clock(out_dir.path(), false, &at(1_667_651_696)).unwrap();
+        match Lock::acquire_with_clock(out_dir.path(), false, &at(1_667_651_696 + later_secs)) {
+            Err(Error::Held { pid, stale, .. }) => {
+                assert_eq!(pid, std::process::id());
+                stale
+            }
+            result => panic!("expected lock to be held, got {result:?}"),
+        }
+    }
+
+    #[test]
+    fn recent_lock_of_running_process_is_not_stale() {
+        assert!(!held_stale_after(60 * 60));
+    }
+
+    #[test]
+    fn day_old_lock_is_stale() {
+        assert!(held_stale_after(25 * 60 * 60));
+    }
+
+    #[test]
+    fn lock_records_time_from_clock() {
+        let out_dir = tempfile::tempdir().unwrap();
+        let _lock = Lock::acquire_with_clock(out_dir.path(), false, &at(1_667_651_696)).unwrap();
+        let contents = std::fs::read_to_string(out_dir.path().join(FILENAME)).unwrap();
+        assert_eq!(
+            parse(&contents),
+            (Some(std::process::id()), Some(1_667_651_696))
+        );
+    }
+
+    #[test]
+    fn lock_is_released_when_dropped() {
+        let out_dir = tempfile::tempdir().unwrap();
+        drop(Lock::acquire_with_clock(out_dir.path(), false, &at(1_667_651_696)).unwrap());
+        Lock::acquire_with_clock(out_dir.path(), false, &at(1_667_651_697)).unwrap();
+    }
+
+    #[test]
+    fn leftover_unlocked_file_is_replaced_without_force() {
+        // As left by a run that crashed, whose lock the operating system released.
+        let out_dir = tempfile::tempdir().unwrap();
+        std::fs::write(out_dir.path().join(FILENAME), "1\n1667651696\n").unwrap();
+        Lock::acquire_with_clock(out_dir.path(), false, &at(1_667_651_697)).unwrap();
+        let contents = std::fs::read_to_string(out_dir.path().join(FILENAME)).unwrap();
+        assert_eq!(parse(&contents).1, Some(1_667_651_697));
+    }
+
+    #[test]
+    fn force_refuses_to_replace_live_lock() {
+        let out_dir = tempfile::tempdir().unwrap();
+        let _lock = Lock::acquire_with_clock(out_dir.path(), false, &at(1_667_651_696)).unwrap();
+        let result = Lock::acquire_with_clock(out_dir.path(), true, &at(1_667_651_756));
+        assert!(matches!(result, Err(Error::Held { stale: false, .. })));
+    }
+
+    #[test]
+    fn force_replaces_stale_lock() {
+        let out_dir = tempfile::tempdir().unwrap();
+        let _hung = Lock::acquire_with_clock(out_dir.path(), false, &at(1_667_651_696)).unwrap();
+        let later = 1_667_651_696 + 25 * 60 * 60;
+        let _lock = Lock::acquire_with_clock(out_dir.path(), true, &at(later)).unwrap();
+        let contents = std::fs::read_to_string(out_dir.path().join(FILENAME)).unwrap();
+        assert_eq!(parse(&contents).1, Some(u64::try_from(later).unwrap()));
+    }
+}
@@ -0,0 +1,334 @@
+//! A listing of every file written by a run, with checksums for later verification.
+
+use crate::bot::ImageRef;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+use twitter_v2::id::NumericId;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Failed to read manifest '{}'", path.display())]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("Failed to write manifest '{}'", path.display())]
+    Write {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("Manifest '{}' is malformed", path.display())]
+    Malformed {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry {
+    /// Filename relative to the output directory, or to the manifest's own directory if
+    /// written with [`relative_prefix`].
+    pub filename: String,
+    pub url: String,
+    pub tweet_id: NumericId,
+    pub username: String,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created_at: time::OffsetDateTime,
+    pub media_key: String,
+    pub media_index: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub permalink: Option<String>,
+    pub size: u64,
+    pub sha256: String,
+}
+
+impl Entry {
+    pub fn new(
+        image_ref: &ImageRef,
+        filename: String,
+        permalink: Option<String>,
+        checksum: Checksum,
+    ) -> Self {
+        Self {
+            filename,
+            url: image_ref.url.to_string(),
+            tweet_id: image_ref.tweet.id,
+            username: image_ref.tweet.username.clone(),
+            created_at: image_ref.tweet.created_at,
+            media_key: image_ref.media_key.clone(),
+            media_index: image_ref.media_index,
+            permalink,
+            size: checksum.size,
+            sha256: checksum.sha256,
+        }
+    }
+}
+
+/// Size and hex-encoded sha256 digest of some content.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checksum {
+    pub size: u64,
+    pub sha256: String,
+}
+
+impl Checksum {
+    pub fn of_bytes(bytes: &[u8]) -> Self {
+        Self {
+            size: bytes.len().try_into().expect("usize in u64"),
+            sha256: format!("{:x}", Sha256::digest(bytes)),
+        }
+    }
+
+    pub fn of_file(path: &Path) -> std::io::Result<Self> {
+        let mut file = std::fs::File::open(path)?;
+        let mut hasher = Sha256::new();
+        let mut buffer = [0; 64 * 1024];
+        let mut size = 0;
+        loop {
+            let read = file.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..read]);
+            size += u64::try_from(read).expect("usize in u64");
+        }
+        Ok(Self {
+            size,
+            sha256: format!("{:x}", hasher.finalize()),
+        })
+    }
+}
+
+/// State of a file listed in a manifest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verified {
+    Ok,
+    Missing,
+    /// The file exists, but its size or checksum differs from the manifest.
+    Corrupted,
+}
+
+/// Prefix that turns a filename relative to the output directory into one relative to
+/// the directory holding the manifest, such as `likes/` for a manifest beside a `likes`
+/// output directory. Both directories must exist.
+pub fn relative_prefix(manifest: &Path, out_dir: &Path) -> std::io::Result<String> {
+    let manifest_dir = manifest_dir(manifest).canonicalize()?;
+    let out_dir = out_dir.canonicalize()?;
+    let shared = manifest_dir
+        .components()
+        .zip(out_dir.components())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let mut prefix = String::new();
+    for _ in manifest_dir.components().skip(shared) {
+        prefix.push_str("../");
+    }
+    for component in out_dir.components().skip(shared) {
+        prefix.push_str(&component.as_os_str().to_string_lossy());
+        prefix.push('/');
+    }
+    Ok(prefix)
+}
+
+/// Directory holding a manifest, which filenames in it are relative to if it was written
+/// with [`relative_prefix`].
+pub fn manifest_dir(manifest: &Path) -> &Path {
+    match manifest.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    }
+}
+
+/// Check a manifest entry against the file in the directory its filename is relative to.
+pub fn verify_entry(out_dir: &Path, entry: &Entry) -> std::io::Result<Verified> {
+    let path = out_dir.join(&entry.filename);
+    if !path.exists() {
+        return Ok(Verified::Missing);
+    }
+    let checksum = Checksum::of_file(&path)?;
+    if checksum.size == entry.size && checksum.sha256 == entry.sha256 {
+        Ok(Verified::Ok)
+    } else {
+        Ok(Verified::Corrupted)
+    }
+}
+
+pub fn load(path: &Path) -> Result<Vec<Entry>> {
+    let contents = std::fs::read(path).map_err(|source| Error::Read {
+        path: path.to_owned(),
+        source,
+    })?;
+    serde_json::from_slice(&contents).map_err(|source| Error::Malformed {
+        path: path.to_owned(),
+        source,
+    })
+}
+
+/// Write a manifest. Entries are sorted by tweet id and media index, so runs over the
+/// same tweets produce identical files regardless of download order.
+pub fn save(path: &Path, entries: &[Entry]) -> Result<()> {
+    let mut sorted: Vec<&Entry> = entries.iter().collect();
+    sorted.sort_by_key(|entry| (entry.tweet_id, entry.media_index));
+    let contents = serde_json::to_vec_pretty(&sorted).expect("manifest is serializable");
+    std::fs::write(path, contents).map_err(|source| Error::Write {
+        path: path.to_owned(),
+        source,
+    })
+}
+
+/// Writes a manifest as entries are added, rather than holding every entry in memory.
+///
+/// The file is a JSON array, as written by [`save`], but with entries in the order they
+/// were added. The array is closed when the writer is finished or dropped, so the file is
+/// still valid after a run that stops early, unless the process is killed outright.
+pub struct StreamWriter {
+    path: PathBuf,
+    out: Option<std::io::BufWriter<std::fs::File>>,
+    entries: usize,
+}
+
+impl StreamWriter {
+    pub fn create(path: &Path) -> Result<Self> {
+        let write_error = |source| Error::Write {
+            path: path.to_owned(),
+            source,
+        };
+        let mut out = std::io::BufWriter::new(std::fs::File::create(path).map_err(write_error)?);
+        out.write_all(b"[").map_err(write_error)?;
+        Ok(Self {
+            path: path.to_owned(),
+            out: Some(out),
+            entries: 0,
+        })
+    }
+
+    pub fn append(&mut self, entry: &Entry) -> Result<()> {
+        let out = self.out.as_mut().expect("manifest writer already closed");
+        let separator: &[u8] = if self.entries == 0 { b"\n  " } else { b",\n  " };
+        out.write_all(separator)
+            .and_then(|()| serde_json::to_writer(&mut *out, entry).map_err(std::io::Error::from))
+            .map_err(|source| Error::Write {
+                path: self.path.clone(),
+                source,
+            })?;
+        self.entries += 1;
+        Ok(())
+    }
+
+    /// Close the array and flush the file.
+    pub fn finish(mut self) -> Result<()> {
+        self.close()
+    }
+
+    fn close(&mut self) -> Result<()> {
+        let Some(mut out) = self.out.take() else {
+            return Ok(());
+        };
+        let end: &[u8] = if self.entries == 0 { b"]\n" } else { b"\n]\n" };
+        out.write_all(end)
+            .and_then(|()| out.flush())
+            .map_err(|source| Error::Write {
+                path: self.path.clone(),
+                source,
+            })
+    }
+}
+
+impl Drop for StreamWriter {
+    fn drop(&mut self) {
+        if let Err(error) = self.close() {
+            log::warn!("{error}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use crate::naming::{NameBuilder, Organize};
+    use pretty_assertions::assert_eq;
+
+    /// Prefix for a manifest at `manifest` and an output directory at `out_dir`, both
+    /// relative to a new temporary directory.
+    fn prefix_in_temp_dir(manifest: &str, out_dir: &str) -> (tempfile::TempDir, String) {
+        let root = tempfile::tempdir().unwrap();
+        let manifest = root.path().join(manifest);
+        let out_dir = root.path().join(out_dir);
+        std::fs::create_dir_all(manifest_dir(&manifest)).unwrap();
+        std::fs::create_dir_all(&out_dir).unwrap();
+        let prefix = relative_prefix(&manifest, &out_dir).unwrap();
+        (root, prefix)
+    }
+
+    #[test]
+    fn relative_prefix_beside_out_dir() {
+        let (_root, prefix) = prefix_in_temp_dir("manifest.json", "likes");
+        assert_eq!(prefix, "likes/");
+    }
+
+    #[test]
+    fn relative_prefix_inside_out_dir() {
+        let (_root, prefix) = prefix_in_temp_dir("likes/manifest.json", "likes");
+        assert_eq!(prefix, "");
+    }
+
+    #[test]
+    fn relative_prefix_in_sibling_of_out_dir() {
+        let (_root, prefix) = prefix_in_temp_dir("manifests/manifest.json", "archive/likes");
+        assert_eq!(prefix, "../archive/likes/");
+    }
+
+    #[test]
+    fn relative_filenames_with_organize_by_resolve_from_manifest() {
+        let root = tempfile::tempdir().unwrap();
+        let manifest = root.path().join("manifests/manifest.json");
+        let out_dir = root.path().join("likes");
+        std::fs::create_dir_all(manifest_dir(&manifest)).unwrap();
+        let names = NameBuilder::new().organize_by(vec![Organize::Author, Organize::Date]);
+        let image_ref = fixtures::image_ref();
+        let path = out_dir.join(names.path(&image_ref));
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, b"photo").unwrap();
+
+        let prefix = relative_prefix(&manifest, &out_dir).unwrap();
+        let filename = format!("{prefix}{}", names.filename(&image_ref));
+        assert_eq!(
+            filename,
+            "../likes/magpie/2022-11/2022-11-05T12:34:56.000000000Z magpie 1588888888888888888 FgShiny.jpg"
+        );
+        let entry = Entry::new(
+            &image_ref,
+            filename,
+            None,
+            Checksum::of_file(&path).unwrap(),
+        );
+        assert_eq!(
+            verify_entry(manifest_dir(&manifest), &entry).unwrap(),
+            Verified::Ok
+        );
+    }
+
+    #[test]
+    fn tweet_id_serializes_as_string() {
+        // Tweet ids exceed 2^53, so JSON consumers that parse numbers as doubles would
+        // lose precision if they were written as numbers.
+        let entry = Entry::new(
+            &fixtures::image_ref(),
+            "FgShiny.jpg".to_owned(),
+            None,
+            Checksum {
+                size: 5,
+                sha256: "0".repeat(64),
+            },
+        );
+        let json = serde_json::to_value(&entry).unwrap();
+        assert_eq!(json["tweet_id"], "1588888888888888888");
+        assert_eq!(serde_json::from_value::<Entry>(json).unwrap(), entry);
+    }
+}
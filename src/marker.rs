@@ -0,0 +1,64 @@
+//! Marker of the newest like archived into an output directory, for incremental runs.
+
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+use twitter_v2::id::NumericId;
+
+/// Name of the marker file, stored in the root of the output directory.
+pub const FILENAME: &str = ".magpie-since.json";
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Failed to read marker '{}'", path.display())]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("Failed to write marker '{}'", path.display())]
+    Write {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("Marker '{}' is malformed", path.display())]
+    Malformed {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Marker {
+    /// Id of the most recently liked tweet at the end of the last run.
+    newest_liked: NumericId,
+}
+
+pub fn path(out_dir: &Path) -> PathBuf {
+    out_dir.join(FILENAME)
+}
+
+/// Load the newest liked tweet id from the last run, if any.
+pub fn load(out_dir: &Path) -> Result<Option<NumericId>> {
+    let path = path(out_dir);
+    let contents = match std::fs::read(&path) {
+        Ok(contents) => contents,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(source) => return Err(Error::Read { path, source }),
+    };
+    let marker: Marker =
+        serde_json::from_slice(&contents).map_err(|source| Error::Malformed { path, source })?;
+    Ok(Some(marker.newest_liked))
+}
+
+/// Replace the marker, so an interrupted write never leaves it partial.
+pub fn save(out_dir: &Path, newest_liked: NumericId) -> Result<()> {
+    let path = path(out_dir);
+    let contents =
+        serde_json::to_vec_pretty(&Marker { newest_liked }).expect("marker is serializable");
+    let partial = path.with_extension("json.part");
+    std::fs::write(&partial, contents)
+        .and_then(|_| std::fs::rename(&partial, &path))
+        .map_err(|source| Error::Write { path, source })
+}
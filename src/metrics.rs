@@ -0,0 +1,98 @@
+//! Run metrics in the Prometheus text format, for node_exporter's textfile collector.
+
+use crate::summary::ArchiveSummary;
+use std::fmt::Write;
+use std::path::Path;
+
+/// Render metrics for a finished run.
+pub fn render(summary: &ArchiveSummary) -> String {
+    let metrics: [(&str, &str, &str, f64); 6] = [
+        (
+            "magpie_downloads_total",
+            "gauge",
+            "Images written to the output directory by the last run.",
+            summary.downloaded as f64,
+        ),
+        (
+            "magpie_bytes_total",
+            "gauge",
+            "Bytes of images written to the output directory by the last run.",
+            summary.bytes as f64,
+        ),
+        (
+            "magpie_failures_total",
+            "gauge",
+            "Downloads that failed in the last run.",
+            summary.errors.len() as f64,
+        ),
+        (
+            "magpie_found_total",
+            "gauge",
+            "Images found in liked tweets by the last run.",
+            summary.found as f64,
+        ),
+        (
+            "magpie_run_duration_seconds",
+            "gauge",
+            "Wall clock time taken by the last run.",
+            summary.duration_secs,
+        ),
+        (
+            "magpie_run_success",
+            "gauge",
+            "Whether the last run finished without error.",
+            if summary.error.is_none() { 1.0 } else { 0.0 },
+        ),
+    ];
+
+    let mut rendered = String::new();
+    for (name, kind, help, value) in metrics {
+        writeln!(rendered, "# HELP {name} {help}").expect("write to string");
+        writeln!(rendered, "# TYPE {name} {kind}").expect("write to string");
+        writeln!(rendered, "{name} {value}").expect("write to string");
+    }
+    rendered
+}
+
+/// Write metrics for a finished run, replacing any previous file atomically so the
+/// collector never reads a partial file.
+pub fn write(path: &Path, summary: &ArchiveSummary) -> std::io::Result<()> {
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".part");
+    std::fs::write(&partial, render(summary))?;
+    std::fs::rename(&partial, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn metrics_are_gauges_of_the_last_run() {
+        let summary = ArchiveSummary {
+            downloaded: 3,
+            bytes: 1024,
+            found: 4,
+            ..Default::default()
+        };
+        let rendered = render(&summary);
+        let types: Vec<&str> = rendered
+            .lines()
+            .filter_map(|line| line.strip_prefix("# TYPE "))
+            .collect();
+        assert_eq!(
+            types,
+            vec![
+                "magpie_downloads_total gauge",
+                "magpie_bytes_total gauge",
+                "magpie_failures_total gauge",
+                "magpie_found_total gauge",
+                "magpie_run_duration_seconds gauge",
+                "magpie_run_success gauge",
+            ]
+        );
+        assert!(rendered.contains("\nmagpie_downloads_total 3\n"));
+        assert!(rendered.contains("\nmagpie_run_success 1\n"));
+    }
+}
@@ -0,0 +1,447 @@
+//! Where media is stored in the output directory.
+
+use crate::bot::ImageRef;
+use std::path::PathBuf;
+use time::format_description::well_known::{Iso8601, Rfc3339};
+use twitter_v2::data::MediaType;
+
+/// A level of subdirectory to sort media into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Organize {
+    /// By kind of media: `photos`, `videos` or `gifs`.
+    Type,
+    /// By the username of the tweet's author.
+    Author,
+    /// By the year and month the tweet was created, as `YYYY-MM`.
+    Date,
+    /// One directory per tweet, named by its creation date and id as `YYYY-MM-DD_<id>`.
+    Tweet,
+}
+
+impl Organize {
+    fn directory(self, image_ref: &ImageRef, username: &str) -> String {
+        match self {
+            Self::Type => match image_ref.kind {
+                MediaType::Photo => "photos",
+                MediaType::Video => "videos",
+                MediaType::AnimatedGif => "gifs",
+            }
+            .to_owned(),
+            Self::Author => username.to_owned(),
+            Self::Date => {
+                let created_at = image_ref.tweet.created_at;
+                format!(
+                    "{:04}-{:02}",
+                    created_at.year(),
+                    u8::from(created_at.month())
+                )
+            }
+            Self::Tweet => {
+                let created_at = image_ref.tweet.created_at;
+                format!(
+                    "{:04}-{:02}-{:02}_{}",
+                    created_at.year(),
+                    u8::from(created_at.month()),
+                    created_at.day(),
+                    image_ref.tweet.id
+                )
+            }
+        }
+    }
+}
+
+/// How the tweet's creation time is written in filenames.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum DateStyle {
+    /// Such as `2022-11-05T12:34:56.000000000Z`.
+    #[default]
+    Iso8601,
+    /// Such as `2022-11-05T12:34:56Z`.
+    Rfc3339,
+    /// Such as `20221105T123456Z`, without separators.
+    Compact,
+}
+
+impl DateStyle {
+    pub fn format(self, date: time::OffsetDateTime) -> String {
+        match self {
+            Self::Iso8601 => date.format(&Iso8601::DEFAULT).expect("format iso8601 date"),
+            Self::Rfc3339 => date.format(&Rfc3339).expect("format rfc3339 date"),
+            Self::Compact => {
+                let date = date.to_offset(time::UtcOffset::UTC);
+                format!(
+                    "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+                    date.year(),
+                    u8::from(date.month()),
+                    date.day(),
+                    date.hour(),
+                    date.minute(),
+                    date.second()
+                )
+            }
+        }
+    }
+}
+
+/// Builds the paths media is stored at, relative to the output directory.
+#[derive(Debug, Clone, Default)]
+pub struct NameBuilder {
+    organize_by: Vec<Organize>,
+    flat_single_media: bool,
+    date_style: DateStyle,
+    lowercase_usernames: bool,
+    normalize_whitespace: bool,
+}
+
+impl NameBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sort media into one subdirectory for each level of organization, in order.
+    pub fn organize_by(mut self, levels: Vec<Organize>) -> Self {
+        self.organize_by = levels;
+        self
+    }
+
+    /// Don't give tweets with only one media their own directory when organizing by
+    /// tweet.
+    pub fn flat_single_media(mut self, flat_single_media: bool) -> Self {
+        self.flat_single_media = flat_single_media;
+        self
+    }
+
+    /// Write the tweet's creation time in filenames in this style.
+    pub fn date_style(mut self, date_style: DateStyle) -> Self {
+        self.date_style = date_style;
+        self
+    }
+
+    /// Lowercase usernames in filenames and directories, so they are consistent on
+    /// case-insensitive filesystems.
+    pub fn lowercase_usernames(mut self, lowercase_usernames: bool) -> Self {
+        self.lowercase_usernames = lowercase_usernames;
+        self
+    }
+
+    /// Trim whitespace from usernames in filenames and directories, and replace any runs
+    /// of whitespace within them with a single `_`.
+    pub fn normalize_whitespace(mut self, normalize_whitespace: bool) -> Self {
+        self.normalize_whitespace = normalize_whitespace;
+        self
+    }
+
+    /// The tweet author's username, as written in paths.
+    fn username(&self, image_ref: &ImageRef) -> String {
+        let mut username = image_ref.tweet.username.clone();
+        if self.normalize_whitespace {
+            username = username.split_whitespace().collect::<Vec<_>>().join("_");
+        }
+        if self.lowercase_usernames {
+            username = username.to_lowercase();
+        }
+        username
+    }
+
+    /// Name of the file itself, from the tweet's creation time, author and id, and the
+    /// media's own filename.
+    pub fn base_filename(&self, image_ref: &ImageRef) -> String {
+        let created_at = self.date_style.format(image_ref.tweet.created_at);
+        format!(
+            "{} {} {} {}",
+            created_at,
+            self.username(image_ref),
+            image_ref.tweet.id,
+            image_ref.internal_filename
+        )
+    }
+
+    fn segments(&self, image_ref: &ImageRef) -> Vec<String> {
+        let username = self.username(image_ref);
+        let mut segments: Vec<String> = self
+            .organize_by
+            .iter()
+            .filter(|level| {
+                !(**level == Organize::Tweet
+                    && self.flat_single_media
+                    && image_ref.tweet.media_count == 1)
+            })
+            .map(|level| level.directory(image_ref, &username))
+            .collect();
+        segments.push(self.base_filename(image_ref));
+        segments
+    }
+
+    /// Path relative to the output directory with `/` separators, as recorded in the
+    /// index and manifest.
+    pub fn filename(&self, image_ref: &ImageRef) -> String {
+        self.segments(image_ref).join("/")
+    }
+
+    /// Path relative to the output directory.
+    pub fn path(&self, image_ref: &ImageRef) -> PathBuf {
+        self.segments(image_ref).into_iter().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use pretty_assertions::assert_eq;
+    use twitter_v2::id::NumericId;
+
+    const BASE_FILENAME: &str =
+        "2022-11-05T12:34:56.000000000Z magpie 1588888888888888888 FgShiny.jpg";
+
+    #[test]
+    fn date_style_iso8601() {
+        assert_eq!(
+            DateStyle::Iso8601.format(fixtures::created_at()),
+            "2022-11-05T12:34:56.000000000Z"
+        );
+    }
+
+    #[test]
+    fn date_style_rfc3339() {
+        assert_eq!(
+            DateStyle::Rfc3339.format(fixtures::created_at()),
+            "2022-11-05T12:34:56Z"
+        );
+    }
+
+    #[test]
+    fn date_style_compact() {
+        assert_eq!(
+            DateStyle::Compact.format(fixtures::created_at()),
+            "20221105T123456Z"
+        );
+    }
+
+    #[test]
+    fn date_style_compact_is_in_utc() {
+        let offset = time::UtcOffset::from_hms(2, 0, 0).unwrap();
+        assert_eq!(
+            DateStyle::Compact.format(fixtures::created_at().to_offset(offset)),
+            "20221105T123456Z"
+        );
+    }
+
+    #[test]
+    fn date_style_defaults_to_iso8601() {
+        assert_eq!(DateStyle::default(), DateStyle::Iso8601);
+    }
+
+    #[test]
+    fn flat_by_default() {
+        let names = NameBuilder::new();
+        assert_eq!(names.filename(&fixtures::image_ref()), BASE_FILENAME);
+        assert_eq!(
+            names.path(&fixtures::image_ref()),
+            PathBuf::from(BASE_FILENAME)
+        );
+    }
+
+    #[test]
+    fn organize_by_each_level() {
+        let image_ref = fixtures::image_ref();
+        for (level, directory) in [
+            (Organize::Type, "photos"),
+            (Organize::Author, "magpie"),
+            (Organize::Date, "2022-11"),
+            (Organize::Tweet, "2022-11-05_1588888888888888888"),
+        ] {
+            let names = NameBuilder::new().organize_by(vec![level]);
+            assert_eq!(
+                names.filename(&image_ref),
+                format!("{directory}/{BASE_FILENAME}")
+            );
+        }
+    }
+
+    #[test]
+    fn organize_by_type_of_video_and_gif() {
+        let names = NameBuilder::new().organize_by(vec![Organize::Type]);
+        let mut image_ref = fixtures::image_ref();
+        image_ref.kind = MediaType::Video;
+        assert!(names.filename(&image_ref).starts_with("videos/"));
+        image_ref.kind = MediaType::AnimatedGif;
+        assert!(names.filename(&image_ref).starts_with("gifs/"));
+    }
+
+    #[test]
+    fn organize_by_levels_in_order() {
+        let names = NameBuilder::new().organize_by(vec![Organize::Date, Organize::Author]);
+        let image_ref = fixtures::image_ref();
+        assert_eq!(
+            names.filename(&image_ref),
+            format!("2022-11/magpie/{BASE_FILENAME}")
+        );
+        assert_eq!(
+            names.path(&image_ref),
+            ["2022-11", "magpie", BASE_FILENAME]
+                .into_iter()
+                .collect::<PathBuf>()
+        );
+    }
+
+    #[test]
+    fn flat_single_media_only_skips_tweet_directory() {
+        let names = NameBuilder::new()
+            .organize_by(vec![Organize::Author, Organize::Tweet])
+            .flat_single_media(true);
+        let image_ref = fixtures::image_ref();
+        assert_eq!(
+            names.filename(&image_ref),
+            format!("magpie/{BASE_FILENAME}")
+        );
+    }
+
+    #[test]
+    fn organize_by_tweet_keeps_multi_image_tweet_together() {
+        let names = NameBuilder::new().organize_by(vec![Organize::Tweet]);
+        let image_refs: Vec<ImageRef> = ["FgOne.jpg", "FgTwo.jpg", "FgThree.jpg", "FgFour.jpg"]
+            .into_iter()
+            .enumerate()
+            .map(|(media_index, internal_filename)| {
+                let mut image_ref = fixtures::image_ref();
+                image_ref.tweet.media_count = 4;
+                image_ref.media_index = media_index;
+                image_ref.internal_filename = internal_filename.to_owned();
+                image_ref
+            })
+            .collect();
+        let filenames: Vec<String> = image_refs
+            .iter()
+            .map(|image_ref| names.filename(image_ref))
+            .collect();
+        assert_eq!(
+            filenames,
+            [
+                "2022-11-05_1588888888888888888/2022-11-05T12:34:56.000000000Z magpie 1588888888888888888 FgOne.jpg",
+                "2022-11-05_1588888888888888888/2022-11-05T12:34:56.000000000Z magpie 1588888888888888888 FgTwo.jpg",
+                "2022-11-05_1588888888888888888/2022-11-05T12:34:56.000000000Z magpie 1588888888888888888 FgThree.jpg",
+                "2022-11-05_1588888888888888888/2022-11-05T12:34:56.000000000Z magpie 1588888888888888888 FgFour.jpg",
+            ]
+        );
+    }
+
+    #[test]
+    fn organize_by_tweet_with_flat_single_media_keeps_multi_image_directory() {
+        let names = NameBuilder::new()
+            .organize_by(vec![Organize::Tweet])
+            .flat_single_media(true);
+        let mut image_ref = fixtures::image_ref();
+        assert_eq!(names.filename(&image_ref), BASE_FILENAME);
+        image_ref.tweet.media_count = 2;
+        assert_eq!(
+            names.filename(&image_ref),
+            format!("2022-11-05_1588888888888888888/{BASE_FILENAME}")
+        );
+    }
+
+    #[test]
+    fn date_style_in_filename() {
+        let names = NameBuilder::new().date_style(DateStyle::Compact);
+        assert_eq!(
+            names.filename(&fixtures::image_ref()),
+            "20221105T123456Z magpie 1588888888888888888 FgShiny.jpg"
+        );
+    }
+
+    #[test]
+    fn normalize_whitespace_in_username() {
+        let mut image_ref = fixtures::image_ref();
+        image_ref.tweet.username = "  mag \t pie ".to_owned();
+        let names = NameBuilder::new()
+            .organize_by(vec![Organize::Author])
+            .normalize_whitespace(true);
+        assert_eq!(
+            names.filename(&image_ref),
+            "mag_pie/2022-11-05T12:34:56.000000000Z mag_pie 1588888888888888888 FgShiny.jpg"
+        );
+    }
+
+    #[test]
+    fn usernames_are_raw_by_default() {
+        let mut image_ref = fixtures::image_ref();
+        image_ref.tweet.username = "Mag Pie".to_owned();
+        assert_eq!(
+            NameBuilder::new().base_filename(&image_ref),
+            "2022-11-05T12:34:56.000000000Z Mag Pie 1588888888888888888 FgShiny.jpg"
+        );
+    }
+
+    #[test]
+    fn lowercase_mixed_case_username() {
+        let mut image_ref = fixtures::image_ref();
+        image_ref.tweet.username = "MagPie_Bird".to_owned();
+        let names = NameBuilder::new()
+            .organize_by(vec![Organize::Author])
+            .lowercase_usernames(true);
+        assert_eq!(
+            names.filename(&image_ref),
+            "magpie_bird/2022-11-05T12:34:56.000000000Z magpie_bird 1588888888888888888 FgShiny.jpg"
+        );
+    }
+
+    #[test]
+    fn mixed_case_usernames_share_a_directory_when_lowercased() {
+        let names = NameBuilder::new()
+            .organize_by(vec![Organize::Author])
+            .lowercase_usernames(true);
+        let mut upper = fixtures::image_ref();
+        upper.tweet.username = "MAGPIE".to_owned();
+        let mut mixed = fixtures::image_ref();
+        mixed.tweet.username = "MagPie".to_owned();
+        assert_eq!(names.filename(&upper), names.filename(&mixed));
+    }
+
+    #[test]
+    fn lowercase_and_normalize_whitespace_together() {
+        let mut image_ref = fixtures::image_ref();
+        image_ref.tweet.username = " Mag  Pie ".to_owned();
+        let names = NameBuilder::new()
+            .lowercase_usernames(true)
+            .normalize_whitespace(true);
+        assert_eq!(
+            names.base_filename(&image_ref),
+            "2022-11-05T12:34:56.000000000Z mag_pie 1588888888888888888 FgShiny.jpg"
+        );
+    }
+
+    #[test]
+    fn media_of_one_tweet_get_distinct_names() {
+        let names = NameBuilder::new().organize_by(vec![Organize::Tweet]);
+        let first = fixtures::image_ref();
+        let mut second = fixtures::image_ref();
+        second.media_index = 1;
+        second.internal_filename = "FgOther.jpg".to_owned();
+        assert_ne!(names.filename(&first), names.filename(&second));
+    }
+
+    #[test]
+    fn organize_by_author_then_date() {
+        let names = NameBuilder::new().organize_by(vec![Organize::Author, Organize::Date]);
+        let image_ref = fixtures::image_ref();
+        assert_eq!(
+            names.filename(&image_ref),
+            format!("magpie/2022-11/{BASE_FILENAME}")
+        );
+        assert_eq!(
+            names.path(&image_ref),
+            ["magpie", "2022-11", BASE_FILENAME]
+                .into_iter()
+                .collect::<PathBuf>()
+        );
+    }
+
+    #[test]
+    fn media_with_same_filename_in_different_tweets_get_distinct_names() {
+        let names = NameBuilder::new();
+        let first = fixtures::image_ref();
+        let mut second = fixtures::image_ref();
+        second.tweet.id = NumericId::new(1_588_888_888_888_888_889);
+        assert_ne!(names.filename(&first), names.filename(&second));
+    }
+}
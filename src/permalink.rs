@@ -0,0 +1,98 @@
+//! Links back to the tweets media was downloaded from.
+
+use crate::bot::TweetRef;
+use twitter_v2::id::NumericId;
+
+/// Host used for links unless another is configured.
+pub const DEFAULT_HOST: &str = "twitter.com";
+
+/// Check a permalink host, such as `x.com` or a nitter instance, optionally with a port.
+pub fn parse_host(host: &str) -> Result<String, String> {
+    let url = url::Url::parse(&format!("https://{host}"))
+        .map_err(|error| format!("invalid host '{host}': {error}"))?;
+    let valid = url.host_str().is_some()
+        && url.username().is_empty()
+        && url.password().is_none()
+        && url.path() == "/"
+        && url.query().is_none()
+        && url.fragment().is_none();
+    if !valid {
+        return Err(format!("expected a bare host name, got '{host}'"));
+    }
+    Ok(host.to_owned())
+}
+
+/// Hosts that serve tweets, and so can appear in a tweet url.
+const TWEET_HOSTS: [&str; 5] = [
+    "twitter.com",
+    "www.twitter.com",
+    "mobile.twitter.com",
+    "x.com",
+    "www.x.com",
+];
+
+/// Extract the tweet id from a tweet url, such as
+/// `https://twitter.com/user/status/12345?s=20`. Links by id, such as
+/// `https://x.com/i/web/status/12345`, are also accepted.
+pub fn parse_tweet_url(tweet_url: &str) -> Result<NumericId, String> {
+    let error = |reason: &str| format!("invalid tweet url '{tweet_url}': {reason}");
+    let url = url::Url::parse(tweet_url).map_err(|parse_error| error(&parse_error.to_string()))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(error("expected an http or https url"));
+    }
+    let host = url.host_str().unwrap_or_default();
+    if !TWEET_HOSTS.contains(&host) {
+        return Err(error(&format!(
+            "expected a Twitter or X host, got '{host}'"
+        )));
+    }
+    let segments: Vec<&str> = url
+        .path_segments()
+        .into_iter()
+        .flatten()
+        .filter(|segment| !segment.is_empty())
+        .collect();
+    let id = match segments.as_slice() {
+        ["i", "web", "status", id, ..] | [_, "status", id, ..] => id,
+        _ => return Err(error("expected a path like '/<user>/status/<id>'")),
+    };
+    id.parse()
+        .map_err(|_| error(&format!("tweet id '{id}' isn't a number")))
+}
+
+/// How links to tweets are formatted.
+#[derive(Debug, Clone)]
+pub struct Permalinks {
+    /// Host to link to, such as `twitter.com`, `x.com` or a nitter instance.
+    pub host: String,
+    /// Link with `i/status/<id>` rather than the author's username. These links still
+    /// resolve if the author changes their username.
+    pub by_id: bool,
+}
+
+impl Default for Permalinks {
+    fn default() -> Self {
+        Self {
+            host: DEFAULT_HOST.to_owned(),
+            by_id: false,
+        }
+    }
+}
+
+impl Permalinks {
+    pub fn link(&self, tweet: &TweetRef) -> String {
+        if self.by_id {
+            self.link_by_id(tweet.id)
+        } else {
+            format!(
+                "https://{}/{}/status/{}",
+                self.host, tweet.username, tweet.id
+            )
+        }
+    }
+
+    /// Link to a tweet by id alone, for when the author's username isn't known.
+    pub fn link_by_id(&self, id: NumericId) -> String {
+        format!("https://{}/i/status/{}", self.host, id)
+    }
+}
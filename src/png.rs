@@ -0,0 +1,99 @@
+//! Embedding tweet metadata in downloaded PNG files, as a text chunk.
+
+use serde::Serialize;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Failed to access '{}'", path.display())]
+    File {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("'{}' is not a valid PNG file", path.display())]
+    Malformed { path: PathBuf },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+
+/// Keyword of the text chunk holding tweet metadata.
+const KEYWORD: &str = "Tweet";
+
+/// Tweet metadata embedded in a PNG file, as JSON.
+#[derive(Debug, Clone, Serialize)]
+pub struct Metadata<'a> {
+    pub author: &'a str,
+    /// Link to the tweet.
+    pub url: &'a str,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created_at: time::OffsetDateTime,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alt_text: Option<&'a str>,
+}
+
+/// Add metadata to the PNG file at `path` as an `iTXt` chunk, straight after the
+/// header. Returns `false`, leaving the file untouched, if it isn't a PNG file. The file
+/// is replaced atomically, so it is left as downloaded if writing fails.
+pub fn embed(path: &Path, metadata: &Metadata) -> Result<bool> {
+    let file_error = |source| Error::File {
+        path: path.to_owned(),
+        source,
+    };
+    let bytes = std::fs::read(path).map_err(file_error)?;
+    if !bytes.starts_with(&SIGNATURE) {
+        return Ok(false);
+    }
+    let header_end = header_end(&bytes).ok_or_else(|| Error::Malformed {
+        path: path.to_owned(),
+    })?;
+
+    let mut data = Vec::new();
+    data.extend_from_slice(KEYWORD.as_bytes());
+    // Null separator, then uncompressed, with no language tag or translated keyword.
+    data.extend_from_slice(&[0, 0, 0, 0, 0]);
+    serde_json::to_writer(&mut data, metadata).expect("metadata is serializable");
+
+    let mut part = path.as_os_str().to_owned();
+    part.push(".part");
+    let part = PathBuf::from(part);
+    std::fs::File::create(&part)
+        .and_then(|mut file| {
+            file.write_all(&bytes[..header_end])?;
+            write_chunk(&mut file, b"iTXt", &data)?;
+            file.write_all(&bytes[header_end..])
+        })
+        .and_then(|()| std::fs::rename(&part, path))
+        .map_err(|source| {
+            let _ = std::fs::remove_file(&part);
+            file_error(source)
+        })?;
+    Ok(true)
+}
+
+/// Offset just past the `IHDR` chunk, which must come first.
+fn header_end(bytes: &[u8]) -> Option<usize> {
+    let length = bytes.get(8..12)?;
+    let length = u32::from_be_bytes(length.try_into().expect("4 bytes"));
+    if bytes.get(12..16)? != b"IHDR" {
+        return None;
+    }
+    // Length, type, data and CRC.
+    let end = 16 + usize::try_from(length).ok()? + 4;
+    (end <= bytes.len()).then_some(end)
+}
+
+fn write_chunk(out: &mut impl Write, kind: &[u8; 4], data: &[u8]) -> std::io::Result<()> {
+    let length = u32::try_from(data.len())
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidInput, "chunk too large"))?;
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(kind);
+    crc.update(data);
+    out.write_all(&length.to_be_bytes())?;
+    out.write_all(kind)?;
+    out.write_all(data)?;
+    out.write_all(&crc.finalize().to_be_bytes())
+}
@@ -0,0 +1,83 @@
+//! Progress indicators shared by the command line tools.
+
+use indicatif::ProgressBar;
+use std::borrow::Cow;
+use std::io::IsTerminal;
+use std::time::Duration;
+
+/// Tick interval used by the spinners in `magpie`.
+pub const DEFAULT_TICK: Duration = Duration::from_millis(120);
+
+/// Interval between progress lines logged when progress can't be drawn.
+pub const LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Whether progress can be drawn, which needs stderr to be a terminal.
+pub fn is_interactive() -> bool {
+    std::io::stderr().is_terminal()
+}
+
+/// Log a line describing the progress every `LOG_INTERVAL`, until it is finished.
+fn log_periodically(progress: &ProgressBar) {
+    let progress = progress.downgrade();
+    std::thread::spawn(move || loop {
+        std::thread::sleep(LOG_INTERVAL);
+        let progress = match progress.upgrade() {
+            Some(progress) if !progress.is_finished() => progress,
+            _ => break,
+        };
+        match progress.length() {
+            Some(length) => log::info!("{} {}/{}", progress.message(), progress.position(), length),
+            None => log::info!("{}", progress.message()),
+        }
+    });
+}
+
+/// A bar counting progress through `length` items, labelled by `message`.
+///
+/// When stderr is not a terminal, the bar is hidden and progress is logged periodically
+/// instead, to keep logs free of control characters.
+pub fn bar(length: u64, message: impl Into<Cow<'static, str>>) -> ProgressBar {
+    let progress = ProgressBar::new(length);
+    progress.set_style(
+        indicatif::ProgressStyle::with_template("{wide_bar} {pos}/{len} {msg}")
+            .expect("invalid progress template"),
+    );
+    progress.set_message(message);
+    if !is_interactive() {
+        progress.set_draw_target(indicatif::ProgressDrawTarget::hidden());
+        log_periodically(&progress);
+    }
+    progress
+}
+
+/// A blue spinner of moving arrows, followed by a message.
+///
+/// When stderr is not a terminal, the spinner is hidden and its message is logged
+/// periodically instead.
+pub fn arrow_spinner(
+    message: impl Into<Cow<'static, str>>,
+    tick_interval: Duration,
+) -> ProgressBar {
+    let progress = ProgressBar::new_spinner();
+    progress.set_style(
+        indicatif::ProgressStyle::with_template("{spinner:.blue} {msg}")
+            .expect("invalid progress template")
+            .tick_strings(&[
+                "▹▹▹▹▹",
+                "▸▹▹▹▹",
+                "▹▸▹▹▹",
+                "▹▹▸▹▹",
+                "▹▹▹▸▹",
+                "▹▹▹▹▸",
+                "▪▪▪▪▪",
+            ]),
+    );
+    progress.set_message(message);
+    if is_interactive() {
+        progress.enable_steady_tick(tick_interval);
+    } else {
+        progress.set_draw_target(indicatif::ProgressDrawTarget::hidden());
+        log_periodically(&progress);
+    }
+    progress
+}
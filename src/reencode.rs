@@ -0,0 +1,142 @@
+//! Re-encoding downloaded photos to smaller formats, trading exact originals for space.
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Failed to access '{}'", path.display())]
+    File {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("Failed to decode '{}'", path.display())]
+    Decode {
+        path: PathBuf,
+        source: image::ImageError,
+    },
+    #[error("Failed to encode '{}': {reason}", path.display())]
+    Encode { path: PathBuf, reason: String },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Quality used when none is given, from 0 to 100.
+const DEFAULT_QUALITY: u8 = 80;
+
+/// Format photos are re-encoded to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Webp,
+    /// Only available when built with the `avif` feature, as the encoder needs `nasm`.
+    Avif,
+}
+
+/// A format and quality to re-encode photos with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reencode {
+    pub format: Format,
+    /// Lossy quality, from 0 to 100.
+    pub quality: u8,
+}
+
+/// Parse a format with an optional quality, such as `webp` or `avif:60`.
+pub fn parse(reencode: &str) -> std::result::Result<Reencode, String> {
+    let (format, quality) = match reencode.split_once(':') {
+        Some((format, quality)) => (format, Some(quality)),
+        None => (reencode, None),
+    };
+    let format = match format {
+        "webp" => Format::Webp,
+        "avif" if cfg!(feature = "avif") => Format::Avif,
+        "avif" => return Err("avif support requires building with the 'avif' feature".into()),
+        _ => return Err(format!("expected 'webp' or 'avif', got '{format}'")),
+    };
+    let quality = match quality {
+        Some(quality) => quality
+            .parse()
+            .ok()
+            .filter(|quality| *quality <= 100)
+            .ok_or_else(|| format!("expected a quality from 0 to 100, got '{quality}'"))?,
+        None => DEFAULT_QUALITY,
+    };
+    Ok(Reencode { format, quality })
+}
+
+/// A filename with its extension replaced, or added if it has none.
+pub fn with_extension(filename: &str, extension: &str) -> String {
+    let name_start = filename.rfind('/').map_or(0, |index| index + 1);
+    match filename[name_start..].rfind('.') {
+        Some(dot) if dot > 0 => format!("{}.{}", &filename[..name_start + dot], extension),
+        _ => format!("{filename}.{extension}"),
+    }
+}
+
+impl Reencode {
+    pub fn extension(&self) -> &'static str {
+        match self.format {
+            Format::Webp => "webp",
+            Format::Avif => "avif",
+        }
+    }
+
+    /// Re-encode the photo at `path` in place. The file is replaced atomically, so it
+    /// is left as downloaded if re-encoding fails.
+    pub fn apply(&self, path: &Path) -> Result<()> {
+        let file_error = |source| Error::File {
+            path: path.to_owned(),
+            source,
+        };
+        let bytes = std::fs::read(path).map_err(file_error)?;
+        let image = image::load_from_memory(&bytes).map_err(|source| Error::Decode {
+            path: path.to_owned(),
+            source,
+        })?;
+        let encoded = self.encode(&image).map_err(|reason| Error::Encode {
+            path: path.to_owned(),
+            reason,
+        })?;
+
+        let mut part = path.as_os_str().to_owned();
+        part.push(".part");
+        let part = PathBuf::from(part);
+        std::fs::File::create(&part)
+            .and_then(|mut file| file.write_all(&encoded))
+            .and_then(|()| std::fs::rename(&part, path))
+            .map_err(|source| {
+                let _ = std::fs::remove_file(&part);
+                file_error(source)
+            })
+    }
+
+    fn encode(&self, image: &image::DynamicImage) -> std::result::Result<Vec<u8>, String> {
+        match self.format {
+            Format::Webp => {
+                // The encoder only accepts 8 bit RGB(A) images.
+                let image = if image.color().has_alpha() {
+                    image::DynamicImage::ImageRgba8(image.to_rgba8())
+                } else {
+                    image::DynamicImage::ImageRgb8(image.to_rgb8())
+                };
+                let encoder = webp::Encoder::from_image(&image).map_err(str::to_owned)?;
+                Ok(encoder.encode(f32::from(self.quality)).to_vec())
+            }
+            #[cfg(feature = "avif")]
+            Format::Avif => {
+                let mut encoded = Vec::new();
+                let encoder = image::codecs::avif::AvifEncoder::new_with_speed_quality(
+                    &mut encoded,
+                    8,
+                    self.quality,
+                );
+                image
+                    .write_with_encoder(encoder)
+                    .map_err(|error| error.to_string())?;
+                Ok(encoded)
+            }
+            #[cfg(not(feature = "avif"))]
+            Format::Avif => Err("built without the 'avif' feature".to_owned()),
+        }
+    }
+}
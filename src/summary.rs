@@ -0,0 +1,202 @@
+//! Totals reported at the end of an archive run.
+
+use serde::Serialize;
+
+/// Most failures listed when a summary is logged. All are kept in the summary itself.
+const LOGGED_FAILURES: usize = 10;
+
+/// Something that happened to one request or item during a run, for debugging runs
+/// that only partly succeed.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum RunEvent {
+    /// A download was retried, such as with a refreshed url.
+    Retry { url: String, reason: String },
+    /// API requests were paused after one was rate limited.
+    RateLimitWait {
+        #[serde(with = "time::serde::rfc3339")]
+        until: time::OffsetDateTime,
+    },
+    /// A tweet or media item was skipped rather than downloaded.
+    Skipped { id: String, reason: String },
+    /// A download failed.
+    Failed {
+        url: String,
+        /// HTTP status of the failed response, if there was one.
+        status: Option<u16>,
+        error: String,
+    },
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ArchiveSummary {
+    /// Images found in liked tweets, before any filters.
+    pub found: usize,
+    /// Images written to the output directory.
+    pub downloaded: usize,
+    /// Total size of the images written.
+    pub bytes: u64,
+    /// Images skipped because they were unchanged since a previous run.
+    pub not_modified: usize,
+    /// Images whose content was already archived under another name.
+    pub duplicates: usize,
+    /// Images whose target path held an unrelated file, handled by `--on-collision`.
+    pub collisions: usize,
+    /// Photos the API returned without a url.
+    pub skipped_no_url: usize,
+    /// Media the API returned with a url that isn't a web url with a filename.
+    pub invalid_url: usize,
+    /// Tweets with photos the API returned without a creation date.
+    pub missing_date: usize,
+    /// Media skipped by `--exclude-ext`.
+    pub excluded_by_extension: usize,
+    /// Media found in tweets marked as possibly sensitive.
+    pub sensitive: usize,
+    /// Media referenced by tweets but missing from API responses.
+    pub missing_media: usize,
+    /// Images dropped by `--max-per-author`.
+    pub dropped_per_author: usize,
+    /// Author avatars written with `--download-author-avatars`.
+    pub avatars: usize,
+    /// Download hooks that failed or exited with a non-zero code.
+    pub hook_failures: usize,
+    /// Authors written with `--export-usernames`.
+    pub exported_usernames: usize,
+    /// Messages for each download that failed.
+    pub errors: Vec<String>,
+    /// Retries, rate limit waits, skips and failures.
+    pub events: Vec<RunEvent>,
+    /// The error that stopped the run, if it failed.
+    pub error: Option<String>,
+    /// Wall clock time taken by the run.
+    pub duration_secs: f64,
+}
+
+impl ArchiveSummary {
+    /// Add the totals of another run to this one, such as for another account.
+    pub fn merge(&mut self, other: ArchiveSummary) {
+        self.found += other.found;
+        self.downloaded += other.downloaded;
+        self.bytes += other.bytes;
+        self.not_modified += other.not_modified;
+        self.duplicates += other.duplicates;
+        self.collisions += other.collisions;
+        self.skipped_no_url += other.skipped_no_url;
+        self.invalid_url += other.invalid_url;
+        self.missing_date += other.missing_date;
+        self.excluded_by_extension += other.excluded_by_extension;
+        self.sensitive += other.sensitive;
+        self.missing_media += other.missing_media;
+        self.dropped_per_author += other.dropped_per_author;
+        self.avatars += other.avatars;
+        self.hook_failures += other.hook_failures;
+        self.exported_usernames += other.exported_usernames;
+        self.errors.extend(other.errors);
+        self.events.extend(other.events);
+    }
+
+    /// Log the summary, omitting counts that are zero and not relevant to every run.
+    pub fn log(&self) {
+        log::info!("Found {} images", self.found);
+        log::info!("Downloaded {} images", self.downloaded);
+        if self.not_modified > 0 {
+            log::info!(
+                "Skipped {} images unchanged since the last run",
+                self.not_modified
+            );
+        }
+        if self.duplicates > 0 {
+            log::info!(
+                "Deduplicated {} images already in the archive",
+                self.duplicates
+            );
+        }
+        if self.collisions > 0 {
+            log::warn!(
+                "Found {} images whose filename was already taken",
+                self.collisions
+            );
+        }
+        if self.skipped_no_url > 0 {
+            log::warn!(
+                "Skipped {} images with no url (withheld or removed media)",
+                self.skipped_no_url
+            );
+        }
+        if self.invalid_url > 0 {
+            log::warn!("Skipped {} media with an invalid url", self.invalid_url);
+        }
+        if self.missing_date > 0 {
+            log::warn!(
+                "Found {} tweets with no creation date, handled by --on-missing-date",
+                self.missing_date
+            );
+        }
+        if self.excluded_by_extension > 0 {
+            log::info!("Excluded {} media by extension", self.excluded_by_extension);
+        }
+        if self.sensitive > 0 {
+            log::info!(
+                "Found {} media in tweets marked as possibly sensitive",
+                self.sensitive
+            );
+        }
+        if self.missing_media > 0 {
+            log::warn!(
+                "Skipped {} media missing from API responses, which may have been truncated",
+                self.missing_media
+            );
+        }
+        if self.dropped_per_author > 0 {
+            log::info!(
+                "Dropped {} images over the per-author limit",
+                self.dropped_per_author
+            );
+        }
+        if self.avatars > 0 {
+            log::info!("Downloaded {} author avatars", self.avatars);
+        }
+        if self.hook_failures > 0 {
+            log::warn!("{} download hooks failed", self.hook_failures);
+        }
+        if self.exported_usernames > 0 {
+            log::info!("Exported {} author usernames", self.exported_usernames);
+        }
+        self.log_events();
+    }
+
+    /// Log counts of each kind of event, and the first few failures.
+    fn log_events(&self) {
+        let (mut retries, mut waits, mut skipped, mut failed) = (0, 0, 0, 0);
+        for event in self.events.iter() {
+            match event {
+                RunEvent::Retry { .. } => retries += 1,
+                RunEvent::RateLimitWait { .. } => waits += 1,
+                RunEvent::Skipped { .. } => skipped += 1,
+                RunEvent::Failed { .. } => failed += 1,
+            }
+        }
+        if retries + waits + skipped + failed == 0 {
+            return;
+        }
+        log::info!(
+            "Events: {retries} retries, {waits} rate limit waits, {skipped} skipped, {failed} failed"
+        );
+        let failures = self.events.iter().filter_map(|event| match event {
+            RunEvent::Failed { url, status, error } => Some((url, status, error)),
+            _ => None,
+        });
+        for (url, status, error) in failures.take(LOGGED_FAILURES) {
+            match status {
+                Some(status) => log::warn!("Failed '{url}' ({status}): {error}"),
+                None => log::warn!("Failed '{url}': {error}"),
+            }
+        }
+        if failed > LOGGED_FAILURES {
+            log::warn!(
+                "...and {} more failures, see --events-json",
+                failed - LOGGED_FAILURES
+            );
+        }
+    }
+}
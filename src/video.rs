@@ -0,0 +1,172 @@
+//! Choosing which variant of a video to download.
+
+use twitter_v2::data::MediaVariant;
+
+const MP4: &str = "video/mp4";
+const HLS: &str = "application/x-mpegURL";
+
+/// Which MP4 variant of a video to download, by bitrate.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum VideoQuality {
+    /// The highest bitrate.
+    #[default]
+    Highest,
+    /// The lowest bitrate.
+    Lowest,
+    /// The highest bitrate at or below this many kilobits per second.
+    AtMost(u32),
+}
+
+/// Parse a video quality given as `highest`, `lowest` or `<=Nk`, such as `<=832k`.
+pub fn parse_quality(quality: &str) -> Result<VideoQuality, String> {
+    match quality {
+        "highest" => Ok(VideoQuality::Highest),
+        "lowest" => Ok(VideoQuality::Lowest),
+        _ => quality
+            .strip_prefix("<=")
+            .and_then(|limit| limit.strip_suffix('k'))
+            .and_then(|limit| limit.parse().ok())
+            .map(VideoQuality::AtMost)
+            .ok_or_else(|| format!("expected 'highest', 'lowest' or '<=Nk', got '{quality}'")),
+    }
+}
+
+/// Pick the variant of a video to download.
+///
+/// Only MP4 variants with a bitrate are ranked. HLS playlists have no bitrate, so if
+/// `allow_hls` is set they are only picked when no MP4 variant is suitable.
+pub fn select_variant(
+    variants: &[MediaVariant],
+    quality: VideoQuality,
+    allow_hls: bool,
+) -> Option<&MediaVariant> {
+    let mut mp4s: Vec<(u32, &MediaVariant)> = variants
+        .iter()
+        .filter(|variant| variant.url.is_some() && variant.content_type.as_deref() == Some(MP4))
+        .filter_map(|variant| {
+            let bit_rate = u32::try_from(variant.bit_rate?).ok()?;
+            Some((bit_rate, variant))
+        })
+        .collect();
+    mp4s.sort_by_key(|(bit_rate, _)| *bit_rate);
+    let mp4 = match quality {
+        VideoQuality::Highest => mp4s.last(),
+        VideoQuality::Lowest => mp4s.first(),
+        VideoQuality::AtMost(kbps) => mp4s
+            .iter()
+            .rev()
+            .find(|(bit_rate, _)| *bit_rate <= kbps.saturating_mul(1000)),
+    };
+    mp4.map(|(_, variant)| *variant).or_else(|| {
+        variants
+            .iter()
+            .filter(|_| allow_hls)
+            .find(|variant| variant.url.is_some() && variant.content_type.as_deref() == Some(HLS))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn variant(content_type: &str, bit_rate: Option<i32>, url: &str) -> MediaVariant {
+        MediaVariant {
+            bit_rate,
+            content_type: Some(content_type.to_owned()),
+            url: Some(url.parse().unwrap()),
+        }
+    }
+
+    fn variants() -> Vec<MediaVariant> {
+        vec![
+            variant(HLS, None, "https://video.twimg.com/pl/playlist.m3u8"),
+            variant(MP4, Some(832_000), "https://video.twimg.com/vid/832.mp4"),
+            variant(MP4, Some(2_176_000), "https://video.twimg.com/vid/2176.mp4"),
+            variant(MP4, Some(256_000), "https://video.twimg.com/vid/256.mp4"),
+        ]
+    }
+
+    fn selected_url(
+        variants: &[MediaVariant],
+        quality: VideoQuality,
+        allow_hls: bool,
+    ) -> Option<&str> {
+        select_variant(variants, quality, allow_hls)
+            .and_then(|variant| variant.url.as_ref())
+            .map(|url| url.as_str())
+    }
+
+    #[test]
+    fn parse_qualities() {
+        assert_eq!(parse_quality("highest"), Ok(VideoQuality::Highest));
+        assert_eq!(parse_quality("lowest"), Ok(VideoQuality::Lowest));
+        assert_eq!(parse_quality("<=832k"), Ok(VideoQuality::AtMost(832)));
+        assert!(parse_quality("832k").is_err());
+        assert!(parse_quality("<=832").is_err());
+        assert!(parse_quality("best").is_err());
+    }
+
+    #[test]
+    fn select_highest_bitrate() {
+        assert_eq!(
+            selected_url(&variants(), VideoQuality::Highest, false),
+            Some("https://video.twimg.com/vid/2176.mp4")
+        );
+    }
+
+    #[test]
+    fn select_lowest_bitrate() {
+        assert_eq!(
+            selected_url(&variants(), VideoQuality::Lowest, false),
+            Some("https://video.twimg.com/vid/256.mp4")
+        );
+    }
+
+    #[test]
+    fn select_highest_bitrate_at_most_limit() {
+        assert_eq!(
+            selected_url(&variants(), VideoQuality::AtMost(832), false),
+            Some("https://video.twimg.com/vid/832.mp4")
+        );
+        assert_eq!(
+            selected_url(&variants(), VideoQuality::AtMost(1000), false),
+            Some("https://video.twimg.com/vid/832.mp4")
+        );
+    }
+
+    #[test]
+    fn no_variant_under_limit_without_hls() {
+        assert_eq!(
+            selected_url(&variants(), VideoQuality::AtMost(100), false),
+            None
+        );
+    }
+
+    #[test]
+    fn hls_only_when_allowed_and_no_mp4_suits() {
+        assert_eq!(
+            selected_url(&variants(), VideoQuality::AtMost(100), true),
+            Some("https://video.twimg.com/pl/playlist.m3u8")
+        );
+        assert_eq!(
+            selected_url(&variants(), VideoQuality::Highest, true),
+            Some("https://video.twimg.com/vid/2176.mp4")
+        );
+    }
+
+    #[test]
+    fn mp4_without_bitrate_or_url_is_ignored() {
+        let mut no_url = variant(MP4, Some(4_000_000), "https://video.twimg.com/vid/x.mp4");
+        no_url.url = None;
+        let variants = vec![
+            variant(MP4, None, "https://video.twimg.com/vid/none.mp4"),
+            no_url,
+            variant(MP4, Some(832_000), "https://video.twimg.com/vid/832.mp4"),
+        ];
+        assert_eq!(
+            selected_url(&variants, VideoQuality::Highest, false),
+            Some("https://video.twimg.com/vid/832.mp4")
+        );
+    }
+}